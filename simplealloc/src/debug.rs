@@ -0,0 +1,106 @@
+/// Heap debugging, enabled with the `heap-debug` feature.
+/// Every allocation is surrounded by red zones:
+/// | header | requested size | FRONT_CANARY | allocation | REAR_CANARY bytes |
+/// and freed memory is filled with POISON. Frees check the header and the red
+/// zones and panic with a hexdump of the block if anything is wrong.
+use super::{Allocator, AllocationHeader, ALLOCATION_ROUNDING_FACTOR};
+use core::fmt;
+
+const WORD: usize = core::mem::size_of::<usize>();
+/// Bytes before and after each allocation.
+pub const RED_ZONE: usize = 2 * WORD;
+pub const OVERHEAD: usize = 2 * RED_ZONE;
+const FRONT_CANARY: usize = 0xcafe_f00d_cafe_f00d_u64 as usize;
+const REAR_CANARY: u8 = 0xfd;
+pub const POISON: u8 = 0x6b;
+// How much of the block to show in reports.
+const DUMP_LEN: usize = 64;
+
+/// xxd-style hexdump, matching the kernel's hexdump!.
+struct HexDump<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for HexDump<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if i % 16 == 0 {
+                write!(f, "{:04x} ", i)?;
+            }
+            write!(f, "{:02x}", b)?;
+            if i % 2 == 1 {
+                write!(f, " ")?;
+            }
+            if i % 16 == 15 {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Panic with a description of the bad block and a dump of its header and
+/// red zone.
+unsafe fn report(what: &str, ptr: *mut u8, header: *mut AllocationHeader, len: usize) -> ! {
+    let bytes = core::slice::from_raw_parts(header as *const u8, len);
+    panic!(
+        "heap: {} at {:p}\nheader {:p}: {:?}\n{}",
+        what,
+        ptr,
+        header,
+        *header,
+        HexDump(bytes)
+    );
+}
+
+/// Write the red zones around a fresh allocation of `request` bytes starting
+/// at `raw`. Returns the pointer to hand out.
+pub unsafe fn arm(raw: *mut u8, request: usize) -> *mut u8 {
+    let words = raw as *mut usize;
+    *words = request;
+    *words.add(1) = FRONT_CANARY;
+    let ptr = raw.add(RED_ZONE);
+    core::ptr::write_bytes(ptr.add(request), REAR_CANARY, RED_ZONE);
+    ptr
+}
+
+/// Check a pointer being freed. Returns the pointer the list allocator
+/// handed out, or panics if this is a foreign pointer, a double free, or the
+/// block was smashed.
+pub unsafe fn check(heap: &Allocator, ptr: *mut u8) -> *mut u8 {
+    let raw = ptr.wrapping_sub(RED_ZONE);
+    let header = AllocationHeader::from_ptr(raw);
+    let arena = match heap.arenas[..heap.arena_count]
+        .iter()
+        .find(|arena| arena.contains(header as *const u8))
+    {
+        Some(arena) => arena,
+        None => panic!("heap: free of foreign pointer {:p}", ptr),
+    };
+    // Only dump as much as we know is in the arena.
+    let dump_len = core::cmp::min(DUMP_LEN, arena.end() as usize - header as usize);
+    let len = (*header).len;
+    if len & (ALLOCATION_ROUNDING_FACTOR - 1) != 0 || len > arena.end() as usize - raw as usize {
+        report("smashed header", ptr, header, dump_len);
+    }
+    if (*header).is_free() {
+        report("double free", ptr, header, dump_len);
+    }
+    let words = raw as *const usize;
+    let request = *words;
+    if *words.add(1) != FRONT_CANARY || request + OVERHEAD > len {
+        report("front red zone smashed", ptr, header, dump_len);
+    }
+    let rear = core::slice::from_raw_parts(ptr.add(request), RED_ZONE);
+    if rear.iter().any(|b| *b != REAR_CANARY) {
+        let dump_len = core::cmp::min(
+            core::mem::size_of::<AllocationHeader>() + RED_ZONE + request + RED_ZONE,
+            arena.end() as usize - header as usize,
+        );
+        report("overflow into rear red zone", ptr, header, dump_len);
+    }
+    raw
+}
+
+/// Fill a block which is being freed with POISON.
+pub unsafe fn poison(header: *mut AllocationHeader) {
+    core::ptr::write_bytes((*header).allocation(), POISON, (*header).len);
+}
@@ -1,8 +1,19 @@
 #![no_std]
 #![allow(clippy::cast_ptr_alignment)]
 
+extern crate alloc;
 extern crate simplespin as spin;
 use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "heap-debug")]
+mod debug;
+pub mod slab;
+pub mod trace;
+pub use slab::KmemCache;
+use trace::{CallerFn, Tracer};
+use core::sync::atomic::AtomicBool;
+use slab::{ObjectCache, SIZE_CLASSES, SLAB_SIZE};
 
 #[derive(Debug)]
 struct AllocationHeader {
@@ -69,18 +80,64 @@ impl AllocationHeader {
         assert!((*splitee).is_free());
         splitee
     }
+    /// The header of a block inside this free one whose allocation can hold
+    /// `size` bytes with `allocation + offset` aligned to `alignment`. If
+    /// this block isn't already aligned there must be room in front for a
+    /// free fragment.
+    unsafe fn aligned_block(&mut self, size: usize, alignment: usize, offset: usize) -> Option<*mut Self> {
+        let start = self.allocation() as usize;
+        let end = self.next() as usize;
+        if (start + offset) & (alignment - 1) == 0 {
+            return if self.len >= size { Some(self) } else { None };
+        }
+        let min_start = start + core::mem::size_of::<Self>() + SPLIT_FUDGE_FACTOR;
+        let aligned = round_up(min_start + offset, alignment) - offset;
+        if aligned + size > end {
+            return None;
+        }
+        Some((aligned - core::mem::size_of::<Self>()) as *mut Self)
+    }
+
+    /// Split this free block in two at `at`. Both halves are free, and `at`
+    /// inherits this block's free list link.
+    unsafe fn split_front(&mut self, at: *mut Self) {
+        assert!(self.is_free());
+        let end = self.next();
+        assert!(self.allocation() < at as *mut u8 && at < end);
+        (*at).len = end as usize - (*at).allocation() as usize;
+        (*at).next_free = self.next_free;
+        self.len = at as usize - self.allocation() as usize;
+        assert_eq!(self.next(), at);
+        assert_eq!((*at).next(), end);
+    }
+
+    /// Whether `other` is the free block directly after this one.
     unsafe fn can_merge(&mut self, other: &Self) -> bool {
         let cother = other as *const Self;
-        self.next() as *const Self == cother && cother == self.next_free as *const Self
+        self.next() as *const Self == cother && other.is_free()
     }
-    unsafe fn merge(&mut self, mut other: &mut Self) {
-        assert!(self.can_merge(&other));
+    /// Absorb the free block after this one. The caller must have taken
+    /// `other` off the free list. This block keeps its own free list link.
+    unsafe fn merge(&mut self, other: &mut Self) {
+        assert!(self.can_merge(other));
         self.len += other.len + core::mem::size_of::<Self>();
-        self.next_free = other.next_free;
         // Not strictly necessary but will help with bugs.
         other.next_free = core::ptr::null_mut();
         other.len = 0;
     }
+    /// Split a used block, keeping the first `request_size` bytes. Returns the
+    /// tail, which is marked used so the caller can free it.
+    unsafe fn split_tail(&mut self, request_size: usize) -> *mut Self {
+        assert!(self.is_splittable(request_size));
+        assert!(!self.is_free());
+        let original_next = self.next();
+        let tail = self.allocation().add(request_size) as *mut Self;
+        (*tail).len = self.len - request_size - core::mem::size_of::<AllocationHeader>();
+        (*tail).next_free = core::ptr::null_mut();
+        self.len = request_size;
+        assert_eq!((*tail).next(), original_next);
+        tail
+    }
     fn is_free(&self) -> bool {
         !self.next_free.is_null()
     }
@@ -89,24 +146,149 @@ impl AllocationHeader {
     }
 }
 
+/// Called when the free list can't satisfy a request. Should return a new
+/// arena of at least the given number of bytes, or None if we're out of
+/// memory.
+pub type GrowFn = fn(usize) -> Option<(*mut u8, usize)>;
+/// Takes back an arena which came from the `GrowFn` once it is entirely free.
+pub type ShrinkFn = fn(*mut u8, usize);
+
+/// The most arenas the heap can be made of. Grow callbacks should hand out
+/// reasonably large arenas.
+pub const MAX_ARENAS: usize = 16;
+
+#[derive(Copy, Clone, Debug)]
+struct Arena {
+    start: *mut u8,
+    len: usize,
+    // Came from the grow callback, so it can be given back.
+    grown: bool,
+}
+
+impl Arena {
+    const EMPTY: Self = Self {
+        start: core::ptr::null_mut(),
+        len: 0,
+        grown: false,
+    };
+
+    unsafe fn end(&self) -> *mut AllocationHeader {
+        // One past the end.
+        self.start.add(self.len) as *mut AllocationHeader
+    }
+
+    fn contains(&self, ptr: *const u8) -> bool {
+        self.start as *const u8 <= ptr && (ptr as usize) < self.start as usize + self.len
+    }
+}
+
 struct Allocator {
-    arena_start: *mut u8,
-    arena_len: usize,
+    arenas: [Arena; MAX_ARENAS],
+    arena_count: usize,
     free_list: *mut AllocationHeader,
+    grow: Option<GrowFn>,
+    shrink: Option<ShrinkFn>,
 }
 
 impl Allocator {
-    unsafe fn arena_end(&mut self) -> *mut AllocationHeader {
-        // One past the end.
-        self.arena_start.add(self.arena_len) as *mut AllocationHeader
+    const fn new() -> Self {
+        Self {
+            arenas: [Arena::EMPTY; MAX_ARENAS],
+            arena_count: 0,
+            free_list: FREE_LIST_END_SENTINEL,
+            grow: None,
+            shrink: None,
+        }
     }
+
+    /// The arena containing the given header.
+    unsafe fn arena_of(&self, header: *mut AllocationHeader) -> &Arena {
+        self.arenas[..self.arena_count]
+            .iter()
+            .find(|arena| arena.contains(header as *const u8))
+            .expect("pointer is not in the heap")
+    }
+
     pub fn init(&mut self, base: *mut u8, len: usize) {
-        unsafe {
-            self.arena_start = base;
-            self.arena_len = len;
-            self.free_list = self.arena_start as *mut AllocationHeader;
-            (*self.free_list).len = self.arena_len - core::mem::size_of::<AllocationHeader>();
-            (*self.free_list).next_free = FREE_LIST_END_SENTINEL;
+        self.arena_count = 0;
+        self.free_list = FREE_LIST_END_SENTINEL;
+        unsafe { self.add_arena(base, len, false) };
+    }
+
+    /// Add a region of memory to the heap as a single free block.
+    unsafe fn add_arena(&mut self, base: *mut u8, len: usize, grown: bool) {
+        assert!(self.arena_count < MAX_ARENAS);
+        assert!(len > core::mem::size_of::<AllocationHeader>());
+        self.arenas[self.arena_count] = Arena { start: base, len, grown };
+        self.arena_count += 1;
+        let block = base as *mut AllocationHeader;
+        (*block).len = len - core::mem::size_of::<AllocationHeader>();
+        (*block).next_free = self.free_list;
+        self.free_list = block;
+    }
+
+    /// Ask the grow callback for an arena which can hold `request` bytes.
+    unsafe fn grow(&mut self, request: usize) -> bool {
+        let grow = match self.grow {
+            Some(grow) if self.arena_count < MAX_ARENAS => grow,
+            _ => return false,
+        };
+        let size = round_up(request, ALLOCATION_ROUNDING_FACTOR) + core::mem::size_of::<AllocationHeader>();
+        match grow(size) {
+            Some((base, len)) => {
+                assert!(len >= size);
+                self.add_arena(base, len, true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Give every grown arena which is entirely free back to the shrink
+    /// callback. Returns the number of bytes released.
+    unsafe fn release_free_arenas(&mut self) -> usize {
+        let shrink = match self.shrink {
+            Some(shrink) => shrink,
+            None => return 0,
+        };
+        let mut released = 0;
+        let mut i = 0;
+        while i < self.arena_count {
+            let arena = self.arenas[i];
+            if !arena.grown || !self.arena_is_free(&arena) {
+                i += 1;
+                continue;
+            }
+            self.unlink_free_in(&arena);
+            self.arena_count -= 1;
+            self.arenas[i] = self.arenas[self.arena_count];
+            self.arenas[self.arena_count] = Arena::EMPTY;
+            shrink(arena.start, arena.len);
+            released += arena.len;
+        }
+        released
+    }
+
+    unsafe fn arena_is_free(&self, arena: &Arena) -> bool {
+        let mut blk = arena.start as *mut AllocationHeader;
+        while blk != arena.end() {
+            if !(*blk).is_free() {
+                return false;
+            }
+            blk = (*blk).next();
+        }
+        true
+    }
+
+    /// Remove every free block inside the arena from the free list.
+    unsafe fn unlink_free_in(&mut self, arena: &Arena) {
+        let mut prev: *mut *mut AllocationHeader = &mut self.free_list;
+        while *prev != FREE_LIST_END_SENTINEL {
+            if arena.contains(*prev as *const u8) {
+                *prev = (**prev).next_free;
+            } else {
+                prev = &mut (**prev).next_free;
+            }
         }
     }
 
@@ -119,55 +301,116 @@ impl Allocator {
         assert!((*free).is_free());
     }
 
-    /*
-    fn arena_start(&self) -> *mut AllocationHeader {
-        self.arena_start as *mut AllocationHeader
+    /// Walk every block in every arena.
+    unsafe fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats::default();
+        for arena in self.arenas[..self.arena_count].iter() {
+            let mut blk = arena.start as *mut AllocationHeader;
+            while blk < arena.end() {
+                if (*blk).is_free() {
+                    stats.bytes_free += (*blk).len;
+                    stats.free_blocks += 1;
+                    stats.largest_free = core::cmp::max(stats.largest_free, (*blk).len);
+                } else {
+                    stats.bytes_in_use += (*blk).len;
+                }
+                blk = (*blk).next();
+            }
+        }
+        stats
     }
-    unsafe fn print(&self, msg: &str) {
-        print!("Heap: {} {{\n", msg);
-        print!("free_list {:x}\n", self.free_list as u64);
+
+    /// Check that the headers in every arena tile it exactly, and that the
+    /// free list holds exactly the free blocks.
+    unsafe fn verify(&self) -> Result<(), HeapError> {
+        let mut free_blocks = 0;
+        for arena in self.arenas[..self.arena_count].iter() {
+            let mut blk = arena.start as *mut AllocationHeader;
+            while blk != arena.end() {
+                let len = (*blk).len;
+                if len == 0 || len & (ALLOCATION_ROUNDING_FACTOR - 1) != 0 {
+                    return Err(HeapError::BadLength(blk as usize, len));
+                }
+                let next = (*blk).allocation() as usize + len;
+                if next > arena.end() as usize {
+                    return Err(HeapError::PastArenaEnd(blk as usize, len));
+                }
+                if (*blk).is_free() {
+                    free_blocks += 1;
+                }
+                blk = next as *mut AllocationHeader;
+            }
+        }
+        let mut listed = 0;
         let mut cur = self.free_list;
         while cur != FREE_LIST_END_SENTINEL {
-            print!("{:?}\n", *cur);
+            if !self.arenas[..self.arena_count]
+                .iter()
+                .any(|arena| arena.contains(cur as *const u8))
+            {
+                return Err(HeapError::FreeListOutsideHeap(cur as usize));
+            }
+            if !(*cur).is_free() {
+                return Err(HeapError::FreeListBlockUsed(cur as usize));
+            }
+            listed += 1;
+            // More entries than free blocks means there's a cycle.
+            if listed > free_blocks {
+                break;
+            }
             cur = (*cur).next_free;
         }
-        print!("}}\n");
-
-        print!("all_blocks {:x}\n", self.free_list as u64);
-        let mut blk = self.arena_start();
-        while blk != self.arena_end() {
-            print!("{:?}\n", *blk);
-            blk = (*blk).next();
-            println!("{:?} {:?}", blk, self.arena_end());
-            assert!(blk <= self.arena_end());
+        if listed != free_blocks {
+            return Err(HeapError::FreeCountMismatch { listed, free_blocks });
         }
-        print!("}}\n");
+        Ok(())
+    }
+
+    unsafe fn alloc(&mut self, request: usize) -> *mut u8 {
+        self.alloc_aligned(request, ALLOCATION_ROUNDING_FACTOR)
     }
-    */
 
+    /// Allocate from the free list, growing the heap if the free list can't
+    /// satisfy the request. With heap debugging the allocation is wrapped in
+    /// red zones.
     unsafe fn alloc_aligned(&mut self, request: usize, alignment: usize) -> *mut u8 {
-        const MAX_ALIGN: usize = 1 << 17;
-        const MIN_ALIGN: usize = ALLOCATION_ROUNDING_FACTOR;
-        assert!(alignment < MAX_ALIGN);
-        assert!(alignment >= MIN_ALIGN);
-        assert!(alignment > core::mem::size_of::<*mut u8>());
-        let ptr = self.alloc(request + alignment);
-        if ptr.is_null() {
-            return ptr;
-        }
-        let aligned_ptr = ((ptr as usize & !(alignment - 1)) + alignment) as *mut u8;
-        let header_ptr = (aligned_ptr as *mut *mut u8).offset(-1);
-        *header_ptr = ptr;
-        assert!(is_ptr_aligned_by(aligned_ptr, alignment));
-        aligned_ptr
+        assert!(alignment.is_power_of_two());
+        let alignment = core::cmp::max(alignment, ALLOCATION_ROUNDING_FACTOR);
+        #[cfg(feature = "heap-debug")]
+        let ptr = {
+            let ptr = self.alloc_grow(request + debug::OVERHEAD, alignment, debug::RED_ZONE);
+            if ptr.is_null() {
+                return ptr;
+            }
+            debug::arm(ptr, request)
+        };
+        #[cfg(not(feature = "heap-debug"))]
+        let ptr = self.alloc_grow(request, alignment, 0);
+        assert!(is_ptr_aligned_by(ptr, alignment));
+        ptr
     }
 
-    unsafe fn dealloc_aligned(&mut self, ptr: *mut u8) {
-        let header_ptr = (ptr as *mut *mut u8).offset(-1);
-        self.dealloc(*header_ptr);
+    unsafe fn alloc_grow(&mut self, request: usize, alignment: usize, offset: usize) -> *mut u8 {
+        let ptr = self.alloc_free_list(request, alignment, offset);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        // Leave room to split off a leading fragment to align the block.
+        let slack = if alignment > ALLOCATION_ROUNDING_FACTOR {
+            alignment + SPLIT_FUDGE_FACTOR + core::mem::size_of::<AllocationHeader>()
+        } else {
+            0
+        };
+        if !self.grow(request + slack) {
+            return ptr;
+        }
+        self.alloc_free_list(request, alignment, offset)
     }
 
-    unsafe fn alloc(&mut self, request: usize) -> *mut u8 {
+    /// Find a free block which can hold `request` bytes where the allocation
+    /// plus `offset` is aligned. If a block has room but isn't aligned, the
+    /// leading part is split off and stays on the free list.
+    unsafe fn alloc_free_list(&mut self, request: usize, alignment: usize, offset: usize) -> *mut u8 {
         let size = round_up(request, ALLOCATION_ROUNDING_FACTOR);
         assert!(size >= request);
         assert!(!self.free_list.is_null());
@@ -176,8 +419,16 @@ impl Allocator {
             &mut self.free_list as *mut *mut AllocationHeader;
         assert!(!cur.is_null());
         while cur != FREE_LIST_END_SENTINEL {
-            if (*cur).len >= size {
+            if let Some(block) = (*cur).aligned_block(size, alignment, offset) {
                 assert!((*cur).is_free());
+                if block != cur {
+                    // The leading fragment keeps its place on the free list,
+                    // with the aligned block right after it.
+                    (*cur).split_front(block);
+                    (*cur).next_free = block;
+                    prev = &mut (*cur).next_free;
+                    cur = block;
+                }
                 if (*cur).is_splittable(size) {
                     let splitee = (*cur).split_free(size);
                     *prev = splitee;
@@ -203,75 +454,321 @@ impl Allocator {
         if ptr.is_null() {
             return;
         }
-        let header = AllocationHeader::from_ptr(ptr);
+        #[cfg(feature = "heap-debug")]
+        let ptr = debug::check(self, ptr);
+        self.free_block(AllocationHeader::from_ptr(ptr));
+    }
+
+    /// Put a used block on the free list, absorbing any free blocks after it.
+    unsafe fn free_block(&mut self, header: *mut AllocationHeader) {
+        let arena_end = self.arena_of(header).end();
         loop {
             let next = (*header).next();
-            assert!(next <= self.arena_end());
-            if next == self.arena_end() {
+            assert!(next <= arena_end);
+            if next == arena_end {
                 break;
             }
             if (*header).can_merge(&*next) {
+                self.unlink_free(next);
                 (*header).merge(&mut *next);
             } else {
                 break;
             }
         }
         assert!(!(*header).is_free());
+        #[cfg(feature = "heap-debug")]
+        debug::poison(header);
         self.push_free(header);
         assert!((*header).is_free());
     }
+
+    /// Take a free block off the free list.
+    unsafe fn unlink_free(&mut self, block: *mut AllocationHeader) {
+        let mut prev: *mut *mut AllocationHeader = &mut self.free_list;
+        while *prev != block {
+            assert!(*prev != FREE_LIST_END_SENTINEL, "free block {:p} not on the free list", block);
+            prev = &mut (**prev).next_free;
+        }
+        *prev = (*block).next_free;
+    }
+
+    /// Resize an allocation without moving it, growing into the free block
+    /// after it or splitting off the tail. Returns false if there's no room.
+    unsafe fn realloc_in_place(&mut self, ptr: *mut u8, new_size: usize) -> bool {
+        let header = AllocationHeader::from_ptr(ptr);
+        let size = round_up(new_size, ALLOCATION_ROUNDING_FACTOR);
+        if size > (*header).len {
+            let next = (*header).next();
+            if next == self.arena_of(header).end()
+                || !(*header).can_merge(&*next)
+                || (*header).len + core::mem::size_of::<AllocationHeader>() + (*next).len < size
+            {
+                return false;
+            }
+            self.unlink_free(next);
+            (*header).merge(&mut *next);
+        }
+        if (*header).is_splittable(size) {
+            let tail = (*header).split_tail(size);
+            self.free_block(tail);
+        }
+        true
+    }
 }
 
 impl Default for Allocator {
     fn default() -> Self {
-        Self {
-            arena_start: core::ptr::null_mut(),
-            arena_len: 0,
-            free_list: FREE_LIST_END_SENTINEL,
-        }
+        Self::new()
     }
 }
 
-#[derive(Default)]
+/// Allocations are counted in power-of-two buckets from 16 bytes, with the
+/// last bucket holding everything bigger.
+pub const SIZE_BUCKETS: usize = 16;
+
+pub fn size_bucket(size: usize) -> usize {
+    let size = core::cmp::max(size, 16).next_power_of_two();
+    core::cmp::min((size.trailing_zeros() - 4) as usize, SIZE_BUCKETS - 1)
+}
+
+/// A snapshot of the heap. Slab pages count as in use.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HeapStats {
+    pub bytes_in_use: usize,
+    pub bytes_free: usize,
+    pub largest_free: usize,
+    pub free_blocks: usize,
+    /// Allocations made since boot, by `size_bucket` of the requested size.
+    pub allocations: [usize; SIZE_BUCKETS],
+}
+
+/// Heap corruption found by `verify`. Addresses are of the bad header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HeapError {
+    BadLength(usize, usize),
+    PastArenaEnd(usize, usize),
+    FreeListOutsideHeap(usize),
+    FreeListBlockUsed(usize),
+    FreeCountMismatch { listed: usize, free_blocks: usize },
+}
+
+/// Small requests are served from slab caches, one per power-of-two size
+/// class. Slabs and large requests come from the list allocator.
 pub struct GlobalAllocator {
-    allocator: spin::Mutex<Allocator>,
+    allocator: spin::IrqMutex<Allocator>,
+    classes: [spin::IrqMutex<ObjectCache>; SIZE_CLASSES],
+    allocations: [AtomicUsize; SIZE_BUCKETS],
+    tracing: AtomicBool,
+    // Allocations in the tracer's table. They are forgotten when freed even
+    // after tracing is disabled.
+    traced: AtomicUsize,
+    tracer: spin::IrqMutex<Tracer>,
 }
 
 unsafe impl Sync for GlobalAllocator {}
 
+impl Default for GlobalAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Only used to initialize the array of counters.
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
 impl GlobalAllocator {
     pub const fn new() -> Self {
         GlobalAllocator {
-            allocator: spin::Mutex::new(Allocator {
-                arena_start: core::ptr::null_mut(),
-                arena_len: 0,
-                free_list: FREE_LIST_END_SENTINEL,
-            }),
+            allocator: spin::IrqMutex::new(Allocator::new()),
+            classes: [
+                spin::IrqMutex::new(ObjectCache::new(16)),
+                spin::IrqMutex::new(ObjectCache::new(32)),
+                spin::IrqMutex::new(ObjectCache::new(64)),
+                spin::IrqMutex::new(ObjectCache::new(128)),
+                spin::IrqMutex::new(ObjectCache::new(256)),
+                spin::IrqMutex::new(ObjectCache::new(512)),
+                spin::IrqMutex::new(ObjectCache::new(1024)),
+                spin::IrqMutex::new(ObjectCache::new(2048)),
+            ],
+            allocations: [ZERO; SIZE_BUCKETS],
+            tracing: AtomicBool::new(false),
+            traced: AtomicUsize::new(0),
+            tracer: spin::IrqMutex::new(Tracer::new()),
         }
     }
 
     pub fn init(&self, base: *mut u8, len: usize) {
         self.allocator.lock().init(base, len);
     }
-}
-unsafe impl GlobalAlloc for GlobalAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+
+    /// Register callbacks to fetch more memory when the heap runs out and to
+    /// give back grown arenas which have become entirely free.
+    /// The callbacks run with the heap locked, so they must not allocate.
+    pub fn register_grow(&self, grow: GrowFn, shrink: ShrinkFn) {
+        let mut allocator = self.allocator.lock();
+        allocator.grow = Some(grow);
+        allocator.shrink = Some(shrink);
+    }
+
+    /// Give grown arenas which are entirely free back to the shrink callback.
+    /// Returns the number of bytes released.
+    pub fn release_free_arenas(&self) -> usize {
+        unsafe { self.allocator.lock().release_free_arenas() }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = unsafe { self.allocator.lock().stats() };
+        for (count, bucket) in stats.allocations.iter_mut().zip(self.allocations.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        stats
+    }
+
+    /// Check the heap for corruption.
+    pub fn verify(&self) -> Result<(), HeapError> {
+        unsafe { self.allocator.lock().verify() }
+    }
+
+    /// Start recording live allocations, blaming each on the address `caller`
+    /// returns. `caller` runs with the tracer locked, so it must not allocate.
+    pub fn enable_tracing(&self, caller: CallerFn) {
+        self.tracer.lock().caller = Some(caller);
+        self.tracing.store(true, Ordering::Release);
+    }
+
+    /// Stop recording. Allocations already recorded are kept until they are
+    /// freed.
+    pub fn disable_tracing(&self) {
+        self.tracing.store(false, Ordering::Release);
+    }
+
+    /// Write the live traced allocations grouped by call site.
+    pub fn dump_leaks(&self, w: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        self.tracer.lock().dump_leaks(w)
+    }
+
+    /// Who to blame for an allocation, or 0 if tracing is off. Inlined so
+    /// the caller function runs in the entry point's frame.
+    #[inline(always)]
+    fn trace_caller(&self) -> usize {
+        if !self.tracing.load(Ordering::Acquire) {
+            return 0;
+        }
+        let caller = self.tracer.lock().caller;
+        caller.map_or(0, |caller| caller())
+    }
+
+    fn trace_alloc(&self, ptr: *mut u8, layout: &Layout, caller: usize) {
+        if ptr.is_null() || !self.tracing.load(Ordering::Acquire) {
+            return;
+        }
+        if self.tracer.lock().record(ptr, layout, caller) {
+            self.traced.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    fn trace_resize(&self, ptr: *mut u8, size: usize) {
+        if self.traced.load(Ordering::Acquire) != 0 {
+            self.tracer.lock().resize(ptr, size);
+        }
+    }
+
+    fn trace_dealloc(&self, ptr: *mut u8) {
+        if self.traced.load(Ordering::Acquire) != 0 && self.tracer.lock().forget(ptr) {
+            self.traced.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    /// Statistics for each size class, smallest first.
+    pub fn class_stats(&self) -> [slab::CacheStats; SIZE_CLASSES] {
+        let mut stats = [slab::CacheStats::default(); SIZE_CLASSES];
+        for (stat, class) in stats.iter_mut().zip(self.classes.iter()) {
+            *stat = class.lock().stats();
+        }
+        stats
+    }
+
+    unsafe fn alloc_untraced(&self, layout: Layout) -> *mut u8 {
+        self.allocations[size_bucket(layout.size())].fetch_add(1, Ordering::Relaxed);
+        // Heap debugging skips the slabs so every allocation gets red zones.
+        if cfg!(feature = "heap-debug") {
+            return self.alloc_large(layout);
+        }
+        match slab::size_class(&layout) {
+            Some(class) => self.classes[class].lock().alloc(|| {
+                self.alloc_large(Layout::from_size_align_unchecked(SLAB_SIZE, SLAB_SIZE))
+            }),
+            None => self.alloc_large(layout),
+        }
+    }
+
+    // The layout must match the one passed to alloc, so it tells us whether
+    // the pointer came from a slab.
+    unsafe fn dealloc_untraced(&self, ptr: *mut u8, layout: Layout) {
+        if cfg!(feature = "heap-debug") {
+            return self.dealloc_large(ptr, layout);
+        }
+        match slab::size_class(&layout) {
+            Some(class) => self.classes[class].lock().free(ptr),
+            None => self.dealloc_large(ptr, layout),
+        }
+    }
+
+    unsafe fn alloc_large(&self, layout: Layout) -> *mut u8 {
         if layout.align() <= ALLOCATION_ROUNDING_FACTOR {
-            (*self).allocator.lock().alloc(layout.size())
+            self.allocator.lock().alloc(layout.size())
         } else {
-            (*self)
-                .allocator
+            self.allocator
                 .lock()
                 .alloc_aligned(layout.size(), layout.align())
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        if layout.align() <= ALLOCATION_ROUNDING_FACTOR {
-            (*self).allocator.lock().dealloc(ptr);
-        } else {
-            (*self).allocator.lock().dealloc_aligned(ptr);
+    unsafe fn dealloc_large(&self, ptr: *mut u8, _layout: Layout) {
+        self.allocator.lock().dealloc(ptr);
+    }
+}
+
+// The entry points aren't inlined so the tracer's caller function is always
+// the same number of frames from whoever called into the allocator.
+unsafe impl GlobalAlloc for GlobalAllocator {
+    #[inline(never)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let caller = self.trace_caller();
+        let ptr = self.alloc_untraced(layout);
+        self.trace_alloc(ptr, &layout, caller);
+        ptr
+    }
+
+    #[inline(never)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let caller = self.trace_caller();
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        // Heap debugging always moves, so the red zones are rewritten.
+        if !cfg!(feature = "heap-debug") {
+            let in_place = match (slab::size_class(&layout), slab::size_class(&new_layout)) {
+                (Some(old), Some(new)) => old == new,
+                (None, None) => self.allocator.lock().realloc_in_place(ptr, new_size),
+                _ => false,
+            };
+            if in_place {
+                self.trace_resize(ptr, new_size);
+                return ptr;
+            }
         }
+        let new = self.alloc_untraced(new_layout);
+        if !new.is_null() {
+            self.trace_alloc(new, &new_layout, caller);
+            core::ptr::copy_nonoverlapping(ptr, new, core::cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.trace_dealloc(ptr);
+        self.dealloc_untraced(ptr, layout);
     }
 }
 
@@ -292,6 +789,8 @@ mod tests {
             heap.dealloc(core::ptr::null_mut());
         }
     }
+    // Counts bytes exactly, which red zones change.
+    #[cfg(not(feature = "heap-debug"))]
     #[test]
     fn test_simple_99_alloc_frees() {
         let mut arena =
@@ -312,6 +811,8 @@ mod tests {
         }
     }
 
+    // Counts bytes exactly, which red zones change.
+    #[cfg(not(feature = "heap-debug"))]
     #[test]
     fn test_simple_100_alloc_frees() {
         // Magic +8 because of split fudge factor
@@ -502,25 +1003,397 @@ mod tests {
         };
     }
 
+    mod grow {
+        extern crate std;
+        use super::super::*;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use std::vec;
+        use std::vec::Vec;
+
+        static GROWN: AtomicUsize = AtomicUsize::new(0);
+        static SHRUNK: AtomicUsize = AtomicUsize::new(0);
+        const GROW_SIZE: usize = 4096;
+
+        fn grow(min: usize) -> Option<(*mut u8, usize)> {
+            let len = core::cmp::max(min, GROW_SIZE);
+            if GROWN.fetch_add(len, Ordering::SeqCst) >= 4 * GROW_SIZE {
+                return None;
+            }
+            let mem: &mut [u64] = Vec::leak(vec![0u64; len / 8]);
+            Some((mem.as_mut_ptr() as *mut u8, len))
+        }
+
+        fn shrink(_base: *mut u8, len: usize) {
+            SHRUNK.fetch_add(len, Ordering::SeqCst);
+        }
+
+        #[test]
+        fn grows_and_releases_arenas() {
+            let mut arena = [0u64; 16];
+            let mut heap: Allocator = Default::default();
+            heap.init(arena.as_mut_ptr() as *mut u8, 128);
+            heap.grow = Some(grow);
+            heap.shrink = Some(shrink);
+            let mut ptrs = Vec::new();
+            // Much more than the first arena holds.
+            for _ in 0..64 {
+                let ptr = unsafe { heap.alloc(64) };
+                assert!(!ptr.is_null());
+                ptrs.push(ptr);
+            }
+            assert!(heap.arena_count > 1);
+            // A request larger than GROW_SIZE gets an arena big enough for it.
+            let big = unsafe { heap.alloc(2 * GROW_SIZE) };
+            assert!(!big.is_null());
+            // Nothing is free yet, so nothing is released.
+            assert_eq!(unsafe { heap.release_free_arenas() }, 0);
+            for ptr in ptrs.drain(..).chain(core::iter::once(big)) {
+                unsafe { heap.dealloc(ptr) };
+            }
+            let released = unsafe { heap.release_free_arenas() };
+            assert_eq!(released, SHRUNK.load(Ordering::SeqCst));
+            assert!(released >= 2 * GROW_SIZE);
+            // Only the original arena is left, and it still works.
+            assert_eq!(heap.arena_count, 1);
+            let ptr = unsafe { heap.alloc(64) };
+            assert!(!ptr.is_null());
+            assert!(heap.arenas[0].contains(ptr));
+            // The grow callback gives up eventually.
+            heap.grow = Some(grow);
+            let mut count = 0;
+            while !unsafe { heap.alloc(1024) }.is_null() {
+                count += 1;
+                assert!(count < 1000);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "heap-debug"))]
+    #[test]
+    fn small_allocations_use_slabs() {
+        let mut arena = [0u8; 64 * SLAB_SIZE];
+        let heap = GlobalAllocator::new();
+        heap.init(arena.as_mut_ptr(), arena.len());
+        let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+        let mut ptrs = [core::ptr::null_mut(); 64];
+        for (i, ptr) in ptrs.iter_mut().enumerate() {
+            let l = layout(1 + i * 7, 1 << (i % 6));
+            *ptr = unsafe { heap.alloc(l) };
+            assert!(!ptr.is_null());
+            assert!(is_ptr_aligned_by(*ptr, l.align()));
+        }
+        let stats = heap.class_stats();
+        assert_eq!(stats.iter().map(|s| s.in_use).sum::<usize>(), ptrs.len());
+        let slabs: usize = stats.iter().map(|s| s.slabs).sum();
+        for (i, ptr) in ptrs.iter().enumerate() {
+            unsafe { heap.dealloc(*ptr, layout(1 + i * 7, 1 << (i % 6))) };
+        }
+        // Freed objects are reused without taking more slabs.
+        for (i, ptr) in ptrs.iter_mut().enumerate() {
+            *ptr = unsafe { heap.alloc(layout(1 + i * 7, 1 << (i % 6))) };
+        }
+        let stats = heap.class_stats();
+        assert_eq!(stats.iter().map(|s| s.slabs).sum::<usize>(), slabs);
+        // Large requests still go to the list allocator, including whole
+        // pages.
+        for &size in [SLAB_SIZE, 2 * SLAB_SIZE].iter() {
+            let big = unsafe { heap.alloc(layout(size, 8)) };
+            assert!(!big.is_null());
+            assert_eq!(heap.class_stats(), stats);
+            unsafe { heap.dealloc(big, layout(size, 8)) };
+        }
+    }
+
+    #[test]
+    fn stats_and_verify() {
+        let mut arena = [0u64; 8 * 1024];
+        let heap = GlobalAllocator::new();
+        heap.init(arena.as_mut_ptr() as *mut u8, 8 * 8 * 1024);
+        let empty = heap.stats();
+        assert_eq!(empty.bytes_in_use, 0);
+        assert_eq!(empty.free_blocks, 1);
+        assert_eq!(empty.largest_free, empty.bytes_free);
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+        let ptrs = [
+            unsafe { heap.alloc(layout(20000)) },
+            unsafe { heap.alloc(layout(5000)) },
+            unsafe { heap.alloc(layout(9000)) },
+            unsafe { heap.alloc(layout(24)) },
+        ];
+        assert_eq!(heap.verify(), Ok(()));
+        let before = heap.stats();
+        unsafe { heap.dealloc(ptrs[1], layout(5000)) };
+        assert_eq!(heap.verify(), Ok(()));
+        let stats = heap.stats();
+        assert_eq!(stats.free_blocks, before.free_blocks + 1);
+        assert!(stats.bytes_in_use >= 20000 + 9000);
+        assert!(stats.largest_free < empty.largest_free);
+        assert_eq!(stats.allocations[size_bucket(24)], 1);
+        assert_eq!(stats.allocations[size_bucket(20000)], 1);
+        assert_eq!(stats.allocations[size_bucket(9000)], 1);
+        assert_eq!(stats.allocations.iter().sum::<usize>(), 4);
+        assert_eq!(size_bucket(1 << 30), SIZE_BUCKETS - 1);
+
+        // Smash the free block's header.
+        unsafe {
+            let header = heap.allocator.lock().free_list;
+            let len = (*header).len;
+            (*header).len = 3;
+            assert_eq!(heap.verify(), Err(HeapError::BadLength(header as usize, 3)));
+            (*header).len = len;
+            // The first block is in use, so it can't be on the free list.
+            let used = arena.as_mut_ptr() as *mut AllocationHeader;
+            (*header).next_free = used;
+            assert_eq!(heap.verify(), Err(HeapError::FreeListBlockUsed(used as usize)));
+        }
+    }
+
+    // Looks at headers directly, which red zones move.
+    #[cfg(not(feature = "heap-debug"))]
+    #[test]
+    fn realloc_in_place() {
+        let mut arena = [0u64; 4096];
+        let mut heap: Allocator = Default::default();
+        heap.init(arena.as_mut_ptr() as *mut u8, 8 * 4096);
+        unsafe {
+            let a = heap.alloc(64);
+            let b = heap.alloc(64);
+            let c = heap.alloc(64);
+            // Nothing free after a.
+            assert!(!heap.realloc_in_place(a, 128));
+            heap.dealloc(b);
+            // Grow a into b, leaving the rest of b free.
+            assert!(heap.realloc_in_place(a, 96));
+            assert_eq!(heap.verify(), Ok(()));
+            assert!((*AllocationHeader::from_ptr(a)).len >= 96);
+            // Shrink it back, the tail merges with what's left of b.
+            assert!(heap.realloc_in_place(a, 16));
+            assert_eq!((*AllocationHeader::from_ptr(a)).len, 16);
+            assert_eq!(heap.verify(), Ok(()));
+            // c is in the way of growing past b.
+            assert!(!heap.realloc_in_place(a, 1024));
+            // Freed blocks merge with free blocks after them.
+            heap.dealloc(c);
+            heap.dealloc(a);
+            assert_eq!(heap.verify(), Ok(()));
+            let stats = heap.stats();
+            assert_eq!(stats.bytes_in_use, 0);
+        }
+    }
+
+    // Heap debugging always moves on realloc.
+    #[cfg(not(feature = "heap-debug"))]
+    #[test]
+    fn global_realloc() {
+        let mut arena = [0u64; 64 * 1024];
+        let heap = GlobalAllocator::new();
+        heap.init(arena.as_mut_ptr() as *mut u8, 8 * 64 * 1024);
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+        unsafe {
+            let small = heap.alloc(layout(20));
+            // Same size class.
+            assert_eq!(heap.realloc(small, layout(20), 30), small);
+            *small = 0x55;
+            // Moves to a bigger class and copies.
+            let bigger = heap.realloc(small, layout(30), 100);
+            assert_ne!(bigger, small);
+            assert_eq!(*bigger, 0x55);
+            // Large allocations at the end of the heap grow in place.
+            let large = heap.alloc(layout(8192));
+            assert_eq!(heap.realloc(large, layout(8192), 65536), large);
+            assert_eq!(heap.realloc(large, layout(65536), 5000), large);
+            assert_eq!(heap.verify(), Ok(()));
+            heap.dealloc(large, layout(5000));
+            heap.dealloc(bigger, layout(100));
+        }
+    }
+
+    mod tracing {
+        extern crate std;
+        use super::super::*;
+        use std::string::String;
+
+        static mut CALLER: usize = 0;
+        fn caller() -> usize {
+            unsafe { CALLER }
+        }
+
+        #[test]
+        fn dump_leaks_by_call_site() {
+            let mut arena = [0u64; 16 * 1024];
+            let heap = GlobalAllocator::new();
+            heap.init(arena.as_mut_ptr() as *mut u8, 8 * 16 * 1024);
+            let layout = |size| Layout::from_size_align(size, 8).unwrap();
+            unsafe {
+                // Not traced.
+                let before = heap.alloc(layout(8));
+                heap.enable_tracing(caller);
+                CALLER = 0x8020_1234;
+                let a = heap.alloc(layout(100));
+                let b = heap.alloc(layout(28));
+                CALLER = 0x8020_5678;
+                let c = heap.alloc(layout(5000));
+                let d = heap.realloc(c, layout(5000), 6000);
+                let e = heap.alloc(layout(64));
+                heap.dealloc(b, layout(28));
+                heap.dealloc(e, layout(64));
+                heap.dealloc(before, layout(8));
+                let mut out = String::new();
+                heap.dump_leaks(&mut out).unwrap();
+                assert_eq!(
+                    out,
+                    "# 2 live allocations, 6100 bytes\n\
+                     = Stack trace =\n\
+                     BYTES            | CALLER\n\
+                     # 1 allocations, 100 bytes\n\
+                     0000000000000064 | 0000000080201234\n\
+                     # 1 allocations, 6000 bytes\n\
+                     0000000000001770 | 0000000080205678\n\
+                     ===============\n"
+                );
+                heap.disable_tracing();
+                // Frees still drop their records.
+                heap.dealloc(a, layout(100));
+                heap.dealloc(d, layout(6000));
+                let mut out = String::new();
+                heap.dump_leaks(&mut out).unwrap();
+                assert!(out.starts_with("# 0 live allocations, 0 bytes\n"));
+                // Nor does anything get recorded.
+                let f = heap.alloc(layout(8));
+                heap.dealloc(f, layout(8));
+                assert_eq!(heap.traced.load(Ordering::Acquire), 0);
+            }
+        }
+    }
+
+    #[cfg(feature = "heap-debug")]
+    mod heap_debug {
+        use super::super::*;
+
+        fn heap(arena: &mut [u8]) -> Allocator {
+            let mut heap: Allocator = Default::default();
+            heap.init(arena.as_mut_ptr(), arena.len());
+            heap
+        }
+
+        #[test]
+        fn freed_memory_is_poisoned() {
+            let mut arena = [0u8; 1024];
+            let mut heap = heap(&mut arena);
+            let ptr = unsafe { heap.alloc(24) };
+            unsafe {
+                core::ptr::write_bytes(ptr, 0, 24);
+                heap.dealloc(ptr);
+                let freed = core::slice::from_raw_parts(ptr, 24);
+                assert!(freed.iter().all(|b| *b == debug::POISON));
+            }
+        }
+
+        #[test]
+        #[should_panic(expected = "double free")]
+        fn double_free() {
+            let mut arena = [0u8; 1024];
+            let mut heap = heap(&mut arena);
+            let ptr = unsafe { heap.alloc(24) };
+            unsafe {
+                heap.dealloc(ptr);
+                heap.dealloc(ptr);
+            }
+        }
+
+        #[test]
+        #[should_panic(expected = "foreign pointer")]
+        fn foreign_pointer() {
+            let mut arena = [0u8; 1024];
+            let mut heap = heap(&mut arena);
+            let mut other = [0u64; 8];
+            unsafe { heap.dealloc(other.as_mut_ptr().add(4) as *mut u8) };
+        }
+
+        #[test]
+        #[should_panic(expected = "rear red zone")]
+        fn overflow() {
+            let mut arena = [0u8; 1024];
+            let mut heap = heap(&mut arena);
+            let ptr = unsafe { heap.alloc(20) };
+            unsafe {
+                *ptr.add(20) = 0;
+                heap.dealloc(ptr);
+            }
+        }
+
+        #[test]
+        #[should_panic(expected = "front red zone")]
+        fn underflow() {
+            let mut arena = [0u8; 1024];
+            let mut heap = heap(&mut arena);
+            let ptr = unsafe { heap.alloc(20) };
+            unsafe {
+                *ptr.sub(1) = 0;
+                heap.dealloc(ptr);
+            }
+        }
+
+        #[test]
+        #[should_panic(expected = "smashed header")]
+        fn smashed_header() {
+            let mut arena = [0u8; 1024];
+            let mut heap = heap(&mut arena);
+            let ptr = unsafe { heap.alloc(20) };
+            unsafe {
+                let header = AllocationHeader::from_ptr(ptr.sub(debug::RED_ZONE));
+                (*header).len = 0x4141_4141;
+                heap.dealloc(ptr);
+            }
+        }
+    }
+
     #[test]
     fn test_aligned_alloc_dealloc() {
-        // only supports alignments up to 2**16.
-        const MAX_ALIGN_INCLUSIVE: usize = 16usize;
-        let mut arena = [0u8; (1 << MAX_ALIGN_INCLUSIVE) * 2];
+        // Up to 2 MiB, for huge pages.
+        const MAX_ALIGN_INCLUSIVE: usize = 21usize;
+        extern crate std;
+        let mut arena = std::vec![0u64; (1 << MAX_ALIGN_INCLUSIVE) * 4 / 8];
         let mut heap: Allocator = Default::default();
-        heap.init(arena.as_mut_ptr(), arena.len());
-        let mut allocations = [core::ptr::null_mut() as *mut u8; 32 - 6];
-        for i in 6..MAX_ALIGN_INCLUSIVE {
+        heap.init(arena.as_mut_ptr() as *mut u8, arena.len() * 8);
+        let mut allocations = [core::ptr::null_mut() as *mut u8; MAX_ALIGN_INCLUSIVE + 1];
+        for (i, allocation) in allocations.iter_mut().enumerate().skip(3) {
             let alignment = 1 << i;
             let ptr = unsafe { heap.alloc_aligned(32, alignment) };
             assert!(!ptr.is_null());
             assert!(is_ptr_aligned_by(ptr, alignment));
-            allocations[i - 1] = ptr;
+            *allocation = ptr;
         }
-        for i in 6..MAX_ALIGN_INCLUSIVE {
+        unsafe { assert_eq!(heap.verify(), Ok(())) };
+        for ptr in allocations.iter().skip(3) {
             unsafe {
-                heap.dealloc_aligned(allocations[i - 1]);
+                heap.dealloc(*ptr);
             }
         }
+        unsafe { assert_eq!(heap.verify(), Ok(())) };
+    }
+
+    // Counts bytes exactly, which red zones change.
+    #[cfg(not(feature = "heap-debug"))]
+    #[test]
+    fn page_aligned_allocations_dont_waste_pages() {
+        const PAGE: usize = 4096;
+        #[repr(align(4096))]
+        struct Pages([u8; 9 * PAGE]);
+        let mut arena = Pages([0; 9 * PAGE]);
+        let mut heap: Allocator = Default::default();
+        heap.init(arena.0.as_mut_ptr(), arena.0.len());
+        // The first header pushes the first page aligned block to the second
+        // page, and the leading fragment stays free.
+        for _ in 0..8 {
+            let ptr = unsafe { heap.alloc_aligned(PAGE - 64, PAGE) };
+            assert!(!ptr.is_null());
+            assert!(is_ptr_aligned_by(ptr, PAGE));
+        }
+        let stats = unsafe { heap.stats() };
+        assert!(stats.free_blocks >= 1);
+        unsafe { assert_eq!(heap.verify(), Ok(())) };
+        // The leading fragment is still usable.
+        assert!(!unsafe { heap.alloc(PAGE - 128) }.is_null());
     }
 }
@@ -0,0 +1,256 @@
+/// Slab caches for small objects.
+/// A cache carves page sized slabs into equal sized objects and keeps the free
+/// ones on an intrusive list, so allocating and freeing are O(1). The global
+/// allocator keeps one cache per power-of-two size class from 16 B to 2 KiB,
+/// and kernel subsystems can make named caches for their own objects with
+/// `KmemCache`.
+use core::alloc::Layout;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+/// Slabs are one page and page aligned.
+pub const SLAB_SIZE: usize = 4096;
+pub const MIN_CLASS_SIZE: usize = 16;
+/// Anything bigger goes to the list allocator. A slab holds at least two
+/// objects, since a slab of one would cost a page per object and empty slabs
+/// are never given back.
+pub const MAX_CLASS_SIZE: usize = SLAB_SIZE / 2;
+/// 16, 32, 64, 128, 256, 512, 1024, 2048.
+pub const SIZE_CLASSES: usize = 8;
+
+/// The size class serving `layout`, or None if it's too big for a slab.
+/// Objects in a class are aligned to the class size, so the class must be at
+/// least as big as the alignment too.
+pub fn size_class(layout: &Layout) -> Option<usize> {
+    let size = core::cmp::max(layout.size(), layout.align());
+    if size > MAX_CLASS_SIZE {
+        return None;
+    }
+    let size = core::cmp::max(size, MIN_CLASS_SIZE).next_power_of_two();
+    Some((size.trailing_zeros() - MIN_CLASS_SIZE.trailing_zeros()) as usize)
+}
+
+pub fn class_size(class: usize) -> usize {
+    MIN_CLASS_SIZE << class
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub object_size: usize,
+    pub in_use: usize,
+    pub free: usize,
+    pub slabs: usize,
+}
+
+/// A free list of equal sized objects. Callers lock it and supply slabs.
+pub struct ObjectCache {
+    size: usize,
+    free_list: *mut FreeObject,
+    stats: CacheStats,
+}
+
+impl ObjectCache {
+    pub const fn new(size: usize) -> Self {
+        Self {
+            size,
+            free_list: core::ptr::null_mut(),
+            stats: CacheStats { object_size: size, in_use: 0, free: 0, slabs: 0 },
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Take an object. If the cache is empty `slab` is called for a fresh
+    /// `SLAB_SIZE` aligned slab, which may return null if there is no memory.
+    /// # Safety
+    /// Slabs must be valid for `SLAB_SIZE` bytes and not used for anything
+    /// else.
+    pub unsafe fn alloc(&mut self, slab: impl FnOnce() -> *mut u8) -> *mut u8 {
+        if self.free_list.is_null() {
+            let slab = slab();
+            if slab.is_null() {
+                return slab;
+            }
+            self.add_slab(slab);
+        }
+        let obj = self.free_list;
+        self.free_list = (*obj).next;
+        self.stats.free -= 1;
+        self.stats.in_use += 1;
+        obj as *mut u8
+    }
+
+    /// Return an object to the cache.
+    /// # Safety
+    /// `ptr` must have come from `alloc` on this cache and not be used again.
+    pub unsafe fn free(&mut self, ptr: *mut u8) {
+        assert!(self.stats.in_use != 0, "free of {:p} into an empty cache", ptr);
+        let obj = ptr as *mut FreeObject;
+        (*obj).next = self.free_list;
+        self.free_list = obj;
+        self.stats.free += 1;
+        self.stats.in_use -= 1;
+    }
+
+    unsafe fn add_slab(&mut self, slab: *mut u8) {
+        assert_eq!(slab as usize & (SLAB_SIZE - 1), 0);
+        let count = SLAB_SIZE / self.size;
+        // Push in reverse so objects are handed out in address order.
+        for i in (0..count).rev() {
+            let obj = slab.add(i * self.size) as *mut FreeObject;
+            (*obj).next = self.free_list;
+            self.free_list = obj;
+        }
+        self.stats.free += count;
+        self.stats.slabs += 1;
+    }
+}
+
+/// A named cache of `T`s for kernel objects, e.g.
+/// `static TASKS: KmemCache<Task> = KmemCache::new("tasks");`
+/// Slabs come from the global allocator and are never given back.
+pub struct KmemCache<T> {
+    name: &'static str,
+    cache: spin::IrqMutex<ObjectCache>,
+    _marker: PhantomData<T>,
+}
+
+// Objects may be freed, and so dropped, on another hart.
+unsafe impl<T: Send> Sync for KmemCache<T> {}
+
+impl<T> KmemCache<T> {
+    /// Each object is big enough to hold a free list link and rounded up to
+    /// its alignment.
+    const OBJECT_SIZE: usize = {
+        let ptr = core::mem::size_of::<*mut u8>();
+        let size = if core::mem::size_of::<T>() > ptr { core::mem::size_of::<T>() } else { ptr };
+        let align = if core::mem::align_of::<T>() > ptr { core::mem::align_of::<T>() } else { ptr };
+        (size + align - 1) & !(align - 1)
+    };
+
+    /// Each cache's lock gets its own lockdep class from the caller.
+    #[track_caller]
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            cache: spin::IrqMutex::new(ObjectCache::new(Self::OBJECT_SIZE)),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().stats()
+    }
+
+    /// Move `value` into a new object. Returns None if out of memory.
+    pub fn alloc(&self, value: T) -> Option<NonNull<T>> {
+        assert!(Self::OBJECT_SIZE <= MAX_CLASS_SIZE, "{} objects are too big for a slab", self.name);
+        let slab = || unsafe {
+            alloc::alloc::alloc(Layout::from_size_align_unchecked(SLAB_SIZE, SLAB_SIZE))
+        };
+        let ptr = NonNull::new(unsafe { self.cache.lock().alloc(slab) } as *mut T)?;
+        unsafe { ptr.as_ptr().write(value) };
+        Some(ptr)
+    }
+
+    /// Drop the object and return it to the cache.
+    /// # Safety
+    /// `ptr` must have come from `alloc` on this cache and not be used again.
+    pub unsafe fn free(&self, ptr: NonNull<T>) {
+        core::ptr::drop_in_place(ptr.as_ptr());
+        self.cache.lock().free(ptr.as_ptr() as *mut u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn size_classes() {
+        let class = |size, align| size_class(&Layout::from_size_align(size, align).unwrap());
+        assert_eq!(class(0, 1), Some(0));
+        assert_eq!(class(1, 1), Some(0));
+        assert_eq!(class(16, 8), Some(0));
+        assert_eq!(class(17, 8), Some(1));
+        assert_eq!(class(8, 64), Some(2));
+        assert_eq!(class(2048, 8), Some(SIZE_CLASSES - 1));
+        assert_eq!(class(2049, 8), None);
+        assert_eq!(class(4096, 8), None);
+        assert_eq!(class(8, 4096), None);
+        assert_eq!(class(8, 8192), None);
+        assert_eq!(class_size(SIZE_CLASSES - 1), MAX_CLASS_SIZE);
+    }
+
+    #[repr(align(4096))]
+    struct Slab([u8; SLAB_SIZE]);
+
+    #[test]
+    fn object_cache_reuses_objects() {
+        let mut slabs = [Slab([0; SLAB_SIZE]), Slab([0; SLAB_SIZE])];
+        let mut slabs = slabs.iter_mut().map(|s| s.0.as_mut_ptr());
+        let mut cache = ObjectCache::new(256);
+        let mut objs = Vec::new();
+        for _ in 0..SLAB_SIZE / 256 {
+            objs.push(unsafe { cache.alloc(|| slabs.next().unwrap()) });
+        }
+        assert_eq!(cache.stats().slabs, 1);
+        assert_eq!(cache.stats().free, 0);
+        for pair in objs.windows(2) {
+            assert_eq!(pair[0] as usize + 256, pair[1] as usize);
+        }
+        let last = objs.pop().unwrap();
+        unsafe { cache.free(last) };
+        assert_eq!(unsafe { cache.alloc(|| panic!("should reuse")) }, last);
+        // Full again, so this takes the second slab.
+        let obj = unsafe { cache.alloc(|| slabs.next().unwrap()) };
+        assert_eq!(obj as usize & (SLAB_SIZE - 1), 0);
+        assert_eq!(cache.stats().slabs, 2);
+        // Out of slabs once that one is used up.
+        let none = || core::ptr::null_mut();
+        for _ in 1..SLAB_SIZE / 256 {
+            assert!(!unsafe { cache.alloc(none) }.is_null());
+        }
+        assert!(unsafe { cache.alloc(none) }.is_null());
+        assert_eq!(cache.stats().in_use, 2 * SLAB_SIZE / 256);
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Task {
+        id: usize,
+        name: [u8; 20],
+    }
+
+    #[test]
+    fn kmem_cache() {
+        static TASKS: KmemCache<Task> = KmemCache::new("tasks");
+        assert_eq!(TASKS.name(), "tasks");
+        let tasks: Vec<NonNull<Task>> =
+            (0..200).map(|id| TASKS.alloc(Task { id, name: [0; 20] }).unwrap()).collect();
+        for (id, task) in tasks.iter().enumerate() {
+            assert_eq!(unsafe { task.as_ref() }.id, id);
+            assert_eq!(task.as_ptr() as usize % core::mem::align_of::<Task>(), 0);
+        }
+        let stats = TASKS.stats();
+        assert_eq!(stats.object_size, 32);
+        assert_eq!(stats.in_use, 200);
+        assert_eq!(stats.slabs, 2);
+        for task in tasks {
+            unsafe { TASKS.free(task) };
+        }
+        assert_eq!(TASKS.stats().in_use, 0);
+        assert_eq!(TASKS.stats().free, 2 * SLAB_SIZE / 32);
+    }
+}
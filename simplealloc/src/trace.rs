@@ -0,0 +1,108 @@
+/// Allocation tracing for finding leaks.
+/// While tracing is enabled every live allocation is recorded with the return
+/// address of its caller, and `dump_leaks` reports them grouped by call site.
+/// The table is fixed size since it can't allocate, and lookups are linear,
+/// so this is only for debugging.
+use core::alloc::Layout;
+use core::fmt;
+
+/// Returns the address allocations should be blamed on, usually the return
+/// address of whoever called into the allocator. It is called directly from
+/// the `GlobalAlloc` entry points, so the number of frames between it and
+/// that caller is the same for every allocation.
+pub type CallerFn = fn() -> usize;
+
+/// Live allocations we can track. Any more are counted but not recorded.
+pub const TRACE_SLOTS: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Allocation {
+    pub ptr: usize,
+    pub size: usize,
+    pub align: usize,
+    pub caller: usize,
+}
+
+impl Allocation {
+    const EMPTY: Self = Self { ptr: 0, size: 0, align: 0, caller: 0 };
+}
+
+pub struct Tracer {
+    live: [Allocation; TRACE_SLOTS],
+    // Allocations which didn't fit in the table.
+    dropped: usize,
+    pub(crate) caller: Option<CallerFn>,
+}
+
+impl Tracer {
+    pub const fn new() -> Self {
+        Self { live: [Allocation::EMPTY; TRACE_SLOTS], dropped: 0, caller: None }
+    }
+
+    /// Returns true if the allocation got a slot in the table.
+    pub fn record(&mut self, ptr: *mut u8, layout: &Layout, caller: usize) -> bool {
+        match self.live.iter_mut().find(|a| a.ptr == 0) {
+            Some(slot) => {
+                *slot = Allocation { ptr: ptr as usize, size: layout.size(), align: layout.align(), caller };
+                true
+            }
+            None => {
+                self.dropped += 1;
+                false
+            }
+        }
+    }
+
+    pub fn resize(&mut self, ptr: *mut u8, size: usize) {
+        if let Some(slot) = self.live.iter_mut().find(|a| a.ptr == ptr as usize) {
+            slot.size = size;
+        }
+    }
+
+    /// Returns true if `ptr` was recorded.
+    pub fn forget(&mut self, ptr: *mut u8) -> bool {
+        match self.live.iter_mut().find(|a| a.ptr == ptr as usize) {
+            Some(slot) => {
+                *slot = Allocation::EMPTY;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn live(&self) -> impl Iterator<Item = &Allocation> {
+        self.live.iter().filter(|a| a.ptr != 0)
+    }
+
+    /// Write the live allocations grouped by call site, in the stack trace
+    /// format scripts/symbolize.py understands. Lines starting with # are
+    /// left alone by the symbolizer.
+    pub fn dump_leaks(&self, w: &mut dyn fmt::Write) -> fmt::Result {
+        let (count, bytes) = self.live().fold((0, 0), |(n, b), a| (n + 1, b + a.size));
+        writeln!(w, "# {} live allocations, {} bytes", count, bytes)?;
+        if self.dropped != 0 {
+            writeln!(w, "# {} allocations were not traced", self.dropped)?;
+        }
+        writeln!(w, "= Stack trace =")?;
+        writeln!(w, "BYTES            | CALLER")?;
+        for (i, site) in self.live.iter().enumerate() {
+            // Only report each call site at its first allocation.
+            if site.ptr == 0 || self.live[..i].iter().any(|a| a.ptr != 0 && a.caller == site.caller) {
+                continue;
+            }
+            let (count, bytes) = self.live[i..]
+                .iter()
+                .filter(|a| a.ptr != 0 && a.caller == site.caller)
+                .fold((0, 0), |(n, b), a| (n + 1, b + a.size));
+            writeln!(w, "# {} allocations, {} bytes", count, bytes)?;
+            writeln!(w, "{:016x} | {:016x}", bytes, site.caller)?;
+        }
+        writeln!(w, "===============")
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,264 @@
+/// Spinlocks which keep interrupts off while held.
+/// If a trap handler takes a lock the interrupted code already holds it spins
+/// forever, so locks shared with trap handlers must mask interrupts.
+use crate::{Owner, PoisonError, TicketMutex, TicketMutexGuard};
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+/// The arch-specific part of masking interrupts.
+pub trait IrqControl {
+    /// Disable interrupts, returning whether they were enabled.
+    fn disable() -> bool;
+    /// Enable interrupts again if they were enabled before `disable`.
+    fn restore(was_enabled: bool);
+}
+
+/// The supervisor interrupt enable bit, sstatus.SIE. Other architectures,
+/// i.e. host builds, have no interrupts to mask.
+pub struct Sie;
+
+#[cfg(target_arch = "riscv64")]
+const SSTATUS_SIE: usize = 1 << 1;
+
+impl IrqControl for Sie {
+    #[inline(always)]
+    fn disable() -> bool {
+        #[cfg(target_arch = "riscv64")]
+        {
+            let sstatus: usize;
+            unsafe {
+                asm!("csrrci $0, sstatus, 2" : "=r"(sstatus) ::: "volatile");
+            }
+            sstatus & SSTATUS_SIE != 0
+        }
+        #[cfg(not(target_arch = "riscv64"))]
+        false
+    }
+
+    #[inline(always)]
+    fn restore(was_enabled: bool) {
+        #[cfg(target_arch = "riscv64")]
+        {
+            if was_enabled {
+                unsafe {
+                    asm!("csrsi sstatus, 2" :::: "volatile");
+                }
+            }
+        }
+        #[cfg(not(target_arch = "riscv64"))]
+        let _ = was_enabled;
+    }
+}
+
+/// A ticket lock which disables interrupts while it is held, and restores
+/// the previous interrupt state when released.
+pub struct IrqMutex<T, I = Sie> {
+    lock: TicketMutex<T>,
+    _irq: PhantomData<I>,
+}
+
+impl<T, I> IrqMutex<T, I> {
+    #[track_caller]
+    pub const fn new(item: T) -> Self {
+        Self {
+            lock: TicketMutex::new(item),
+            _irq: PhantomData,
+        }
+    }
+}
+
+// Not derived, which would need I: Default too.
+impl<T: Default, I> Default for IrqMutex<T, I> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T, I: IrqControl> IrqMutex<T, I> {
+    /// See `TicketMutex::lock`.
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<'_, T, I> {
+        match self.lock_checked() {
+            Ok(guard) => guard,
+            Err(err) => panic!("{:?}", err),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock_checked(
+        &self,
+    ) -> Result<IrqMutexGuard<'_, T, I>, PoisonError<IrqMutexGuard<'_, T, I>>> {
+        // Interrupts go off first, so a trap can't arrive while we hold the
+        // lock with them still on.
+        let was_enabled = I::disable();
+        let guard = |guard| IrqMutexGuard {
+            guard: ManuallyDrop::new(guard),
+            was_enabled,
+            _irq: PhantomData,
+        };
+        match self.lock.lock_checked() {
+            Ok(g) => Ok(guard(g)),
+            Err(err) => {
+                let owner = err.owner();
+                Err(PoisonError {
+                    guard: guard(err.into_inner()),
+                    owner,
+                })
+            }
+        }
+    }
+
+    pub fn owner(&self) -> Option<Owner> {
+        self.lock.owner()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.lock.is_poisoned()
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T, I>> {
+        let was_enabled = I::disable();
+        match self.lock.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                was_enabled,
+                _irq: PhantomData,
+            }),
+            None => {
+                I::restore(was_enabled);
+                None
+            }
+        }
+    }
+
+    /// Busts the lock
+    /// # Safety
+    /// See `TicketMutex::bust_lock`. Interrupts are left as they are.
+    pub unsafe fn bust_lock(&self) {
+        self.lock.bust_lock();
+    }
+}
+
+pub struct IrqMutexGuard<'a, T, I: IrqControl = Sie> {
+    guard: ManuallyDrop<TicketMutexGuard<'a, T>>,
+    was_enabled: bool,
+    _irq: PhantomData<I>,
+}
+
+impl<'a, T, I: IrqControl> Drop for IrqMutexGuard<'a, T, I> {
+    fn drop(&mut self) {
+        // Release the lock before interrupts can come back.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        I::restore(self.was_enabled);
+    }
+}
+
+impl<'a, T, I: IrqControl> Deref for IrqMutexGuard<'a, T, I> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T, I: IrqControl> DerefMut for IrqMutexGuard<'a, T, I> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+unsafe impl<T: Send, I> Sync for IrqMutex<T, I> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // Each test thread has its own interrupt state.
+    std::thread_local! {
+        #[allow(clippy::missing_const_for_thread_local)]
+        static ENABLED: Cell<bool> = Cell::new(true);
+    }
+
+    struct FakeIrq;
+
+    impl IrqControl for FakeIrq {
+        fn disable() -> bool {
+            ENABLED.with(|e| e.replace(false))
+        }
+        fn restore(was_enabled: bool) {
+            if was_enabled {
+                ENABLED.with(|e| e.set(true));
+            }
+        }
+    }
+
+    fn enabled() -> bool {
+        ENABLED.with(|e| e.get())
+    }
+
+    #[test]
+    fn masks_interrupts_while_held() {
+        let m: IrqMutex<usize, FakeIrq> = IrqMutex::new(0);
+        assert!(enabled());
+        {
+            let mut g = m.lock();
+            assert!(!enabled());
+            *g += 1;
+        }
+        assert!(enabled());
+        assert_eq!(*m.lock(), 1);
+    }
+
+    #[test]
+    fn default() {
+        let m: IrqMutex<usize> = IrqMutex::default();
+        assert_eq!(*m.lock(), 0);
+    }
+
+    #[test]
+    fn nested_locks_restore_in_order() {
+        let a: IrqMutex<(), FakeIrq> = IrqMutex::new(());
+        let b: IrqMutex<(), FakeIrq> = IrqMutex::new(());
+        let ga = a.lock();
+        let gb = b.lock();
+        drop(gb);
+        // Still inside a's critical section.
+        assert!(!enabled());
+        drop(ga);
+        assert!(enabled());
+    }
+
+    #[test]
+    fn already_disabled_stays_disabled() {
+        let m: IrqMutex<(), FakeIrq> = IrqMutex::new(());
+        FakeIrq::disable();
+        drop(m.lock());
+        assert!(!enabled());
+        FakeIrq::restore(true);
+    }
+
+    #[test]
+    fn records_caller() {
+        let m: IrqMutex<(), FakeIrq> = IrqMutex::new(());
+        let line = line!() + 1;
+        let _g = m.lock();
+        let owner = m.owner().unwrap();
+        assert!(owner.location.file().ends_with("irq.rs"));
+        assert_eq!(owner.location.line(), line);
+    }
+
+    #[test]
+    fn failed_try_lock_restores() {
+        let m: IrqMutex<(), FakeIrq> = IrqMutex::new(());
+        let g = m.lock();
+        assert!(m.try_lock().is_none());
+        assert!(!enabled());
+        drop(g);
+        assert!(enabled());
+        assert!(m.try_lock().is_some());
+        assert!(enabled());
+    }
+}
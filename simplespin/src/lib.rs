@@ -1,67 +1,540 @@
 #![no_std]
+#![cfg_attr(target_arch = "riscv64", feature(asm))]
+#[cfg(test)]
+extern crate std;
 use core::cell::UnsafeCell;
+use core::fmt;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 
-// FIXME: The Rustonomicon has a passage about poisoning data during a panic
-// unwind...
+mod irq;
+#[cfg(feature = "lockdep")]
+mod lockdep;
+mod once;
+mod ring;
+mod rwlock;
+mod seqlock;
+pub use irq::{IrqControl, IrqMutex, IrqMutexGuard, Sie};
+pub use once::{Lazy, Once};
+pub use ring::ByteRing;
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use seqlock::SeqLock;
 
-#[derive(Default)]
-pub struct Mutex<T> {
-    lock: AtomicBool,
+// Poisoning: the kernel doesn't unwind, so a hart which panics holding a lock
+// never releases it. Instead the panic handler calls `mark_panicked`, and a
+// hart waiting for a lock whose owner has panicked releases it on the owner's
+// behalf and marks it poisoned, since the data may be half updated. Locks a
+// hart takes after it has panicked belong to its panic handler, which is still
+// running, so they're left alone.
+
+/// Harts which have panicked, one bit each.
+static PANICKED: AtomicUsize = AtomicUsize::new(0);
+
+/// The current hart's id, which the kernel keeps in tp.
+#[inline(always)]
+pub fn hart_id() -> usize {
+    #[cfg(target_arch = "riscv64")]
+    {
+        let id: usize;
+        unsafe {
+            asm!("mv $0, tp" : "=r"(id));
+        }
+        id
+    }
+    #[cfg(all(not(target_arch = "riscv64"), test))]
+    {
+        tests::HART.with(|h| h.get())
+    }
+    #[cfg(all(not(target_arch = "riscv64"), not(test)))]
+    {
+        0
+    }
+}
+
+fn hart_bit(hart: usize) -> usize {
+    1 << (hart % usize::MAX.count_ones() as usize)
+}
+
+/// Record that this hart has panicked, so locks it holds count as poisoned.
+pub fn mark_panicked() {
+    PANICKED.fetch_or(hart_bit(hart_id()), Ordering::SeqCst);
+}
+
+fn has_panicked(hart: usize) -> bool {
+    PANICKED.load(Ordering::SeqCst) & hart_bit(hart) != 0
+}
+
+/// Tell the CPU we're spinning.
+#[inline(always)]
+pub fn cpu_relax() {
+    // Zihintpause's pause, which older assemblers don't know.
+    #[cfg(all(target_arch = "riscv64", target_feature = "zihintpause"))]
+    unsafe {
+        asm!(".word 0x0100000f" :::: "volatile");
+    }
+    #[cfg(not(all(target_arch = "riscv64", target_feature = "zihintpause")))]
+    core::hint::spin_loop();
+    // Host tests may have more threads than CPUs, and the lock holder needs
+    // to run for waiters to make progress.
+    #[cfg(test)]
+    std::thread::yield_now();
+}
+
+/// Which hart holds a lock, and where it took it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Owner {
+    pub hart: usize,
+    pub location: &'static Location<'static>,
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "hart {} at {}:{}",
+            self.hart,
+            self.location.file(),
+            self.location.line()
+        )
+    }
+}
+
+const NO_HART: usize = usize::MAX;
+
+/// An `Owner` which can be updated without a lock.
+struct AtomicOwner {
+    hart: AtomicUsize,
+    location: AtomicPtr<Location<'static>>,
+}
+
+impl AtomicOwner {
+    const fn new() -> Self {
+        Self {
+            hart: AtomicUsize::new(NO_HART),
+            location: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn store(&self, owner: Owner) {
+        self.location.store(
+            owner.location as *const Location as *mut Location,
+            Ordering::Relaxed,
+        );
+        self.hart.store(owner.hart, Ordering::Release);
+    }
+
+    fn load(&self) -> Option<Owner> {
+        let hart = self.hart.load(Ordering::Acquire);
+        let location = self.location.load(Ordering::Relaxed);
+        if hart == NO_HART || location.is_null() {
+            return None;
+        }
+        Some(Owner {
+            hart,
+            location: unsafe { &*location },
+        })
+    }
+}
+
+impl Default for AtomicOwner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returned when locking a mutex whose last owner panicked while holding it.
+/// The lock is held anyway, `into_inner` gets the guard.
+pub struct PoisonError<G> {
+    guard: G,
+    owner: Owner,
+}
+
+impl<G> PoisonError<G> {
+    /// The hart which panicked holding the lock.
+    pub fn owner(&self) -> Owner {
+        self.owner
+    }
+
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+}
+
+impl<G> fmt::Debug for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "lock poisoned by {}", self.owner)
+    }
+}
+
+/// A FIFO spinlock. Each locker takes a ticket and waits for it to be served,
+/// so no hart can starve.
+pub struct TicketMutex<T> {
+    next: AtomicUsize,
+    serving: AtomicUsize,
+    // The ticket the owner took, so we know the owner is current.
+    owner_ticket: AtomicUsize,
+    owner: AtomicOwner,
+    // The owner had already panicked when it took the lock.
+    owner_panicked: AtomicBool,
+    poisoned: AtomicOwner,
+    #[cfg(feature = "lockdep")]
+    class: lockdep::LockClass,
     item: UnsafeCell<T>,
 }
 
-impl<T> Mutex<T> {
+impl<T: Default> Default for TicketMutex<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Ticket locks are fair, so they're our default lock.
+pub type Mutex<T> = TicketMutex<T>;
+pub type MutexGuard<'a, T> = TicketMutexGuard<'a, T>;
+
+impl<T> TicketMutex<T> {
+    /// With lockdep, locks made here share a class with every other lock made
+    /// at the same call site.
+    #[track_caller]
     pub const fn new(item: T) -> Self {
         Self {
-            lock: AtomicBool::new(false), item: UnsafeCell::new(item),
+            next: AtomicUsize::new(0),
+            serving: AtomicUsize::new(0),
+            owner_ticket: AtomicUsize::new(usize::MAX),
+            owner: AtomicOwner::new(),
+            owner_panicked: AtomicBool::new(false),
+            poisoned: AtomicOwner::new(),
+            #[cfg(feature = "lockdep")]
+            class: lockdep::LockClass::new(Location::caller()),
+            item: UnsafeCell::new(item),
+        }
+    }
+
+    /// Take the lock. Panics if the lock is poisoned, use `lock_checked` where
+    /// the data is still usable.
+    #[track_caller]
+    pub fn lock(&self) -> TicketMutexGuard<'_, T> {
+        match self.lock_checked() {
+            Ok(guard) => guard,
+            Err(err) => panic!("{:?}", err),
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<T> {
-        while !self.lock.compare_and_swap(false, true, Ordering::SeqCst) {
-            // Need to find a riscv equivalent...
-            //unsafe { asm!("pause" :::: "volatile"); }
+    /// Take the lock, or report that it is poisoned.
+    #[track_caller]
+    pub fn lock_checked(
+        &self,
+    ) -> Result<TicketMutexGuard<'_, T>, PoisonError<TicketMutexGuard<'_, T>>> {
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.class, Location::caller());
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        loop {
+            let serving = self.serving.load(Ordering::Acquire);
+            if serving == ticket {
+                break;
+            }
+            self.release_if_panicked(serving);
+            cpu_relax();
         }
-        MutexGuard {
-            lock: &self.lock,
-            item: unsafe { &mut *self.item.get() },
+        let guard = self.acquired(ticket, Location::caller());
+        match self.poisoned.load() {
+            Some(owner) => Err(PoisonError { guard, owner }),
+            None => Ok(guard),
         }
     }
 
+    /// Take the lock only if nobody holds it or is waiting for it.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<TicketMutexGuard<'_, T>> {
+        let ticket = self.serving.load(Ordering::Acquire);
+        self.next
+            .compare_exchange(ticket, ticket + 1, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| {
+                #[cfg(feature = "lockdep")]
+                lockdep::acquired(&self.class, Location::caller());
+                self.acquired(ticket, Location::caller())
+            })
+    }
+
+    fn acquired(
+        &self,
+        ticket: usize,
+        location: &'static Location<'static>,
+    ) -> TicketMutexGuard<'_, T> {
+        let hart = hart_id();
+        self.owner.store(Owner { hart, location });
+        self.owner_panicked
+            .store(has_panicked(hart), Ordering::Relaxed);
+        self.owner_ticket.store(ticket, Ordering::Release);
+        TicketMutexGuard { mutex: self }
+    }
+
+    /// If the owner of ticket `serving` panicked holding the lock it will
+    /// never unlock, so poison the lock and unlock it for them.
+    fn release_if_panicked(&self, serving: usize) {
+        // The owner may not have recorded itself yet.
+        if self.owner_ticket.load(Ordering::Acquire) != serving
+            || self.owner_panicked.load(Ordering::Relaxed)
+        {
+            return;
+        }
+        match self.owner.load() {
+            Some(owner) if has_panicked(owner.hart) => {
+                // Other waiters may race us here, but they all record the
+                // same owner and only one moves serving on.
+                self.poisoned.store(owner);
+                let _ = self.serving.compare_exchange(
+                    serving,
+                    serving + 1,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// Who holds the lock, if anyone.
+    pub fn owner(&self) -> Option<Owner> {
+        let serving = self.serving.load(Ordering::Acquire);
+        if self.next.load(Ordering::Relaxed) == serving
+            || self.owner_ticket.load(Ordering::Acquire) != serving
+        {
+            return None;
+        }
+        self.owner.load()
+    }
+
+    /// Whether a hart panicked while holding the lock.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load().is_some()
+    }
+
     /// Busts the lock
     /// # Safety
     /// Lock busting is obviously unsafe. It's potentially useful in last-ditch
     /// panic scenarios, but even then you can't be sure that your thread was the
-    /// one holding the lock when the panic occurred.
+    /// one holding the lock when the panic occurred. Harts already waiting for
+    /// the lock will keep waiting.
     pub unsafe fn bust_lock(&self) {
-        self.lock.store(false, Ordering::SeqCst);
+        self.serving.store(self.next.load(Ordering::SeqCst), Ordering::SeqCst);
     }
 }
 
-pub struct MutexGuard<'a, T> {
-    lock: &'a AtomicBool,
-    item: &'a mut T,
+pub struct TicketMutexGuard<'a, T> {
+    mutex: &'a TicketMutex<T>,
 }
 
-impl<'a, T> Drop for MutexGuard<'a, T> {
+impl<'a, T> Drop for TicketMutexGuard<'a, T> {
     fn drop(&mut self) {
-        self.lock.store(false, Ordering::SeqCst);
+        #[cfg(feature = "lockdep")]
+        lockdep::release(&self.mutex.class);
+        self.mutex.serving.fetch_add(1, Ordering::Release);
     }
 }
 
-impl<'a, T> Deref for MutexGuard<'a, T> {
+impl<'a, T> Deref for TicketMutexGuard<'a, T> {
     type Target = T;
     fn deref(&self) -> &T {
-        &self.item
+        unsafe { &*self.mutex.item.get() }
     }
 }
 
-impl<'a, T> DerefMut for MutexGuard<'a, T> {
+impl<'a, T> DerefMut for TicketMutexGuard<'a, T> {
     fn deref_mut(&mut self) -> &mut T {
-        &mut self.item
+        unsafe { &mut *self.mutex.item.get() }
     }
 }
 
-unsafe impl<T> Sync for Mutex<T> {}
+unsafe impl<T: Send> Sync for TicketMutex<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use std::vec::Vec;
+
+    // Test threads are all hart 0 unless they say otherwise. Harts which
+    // panic get their own ids so they don't poison other tests' locks.
+    std::thread_local! {
+        #[allow(clippy::missing_const_for_thread_local)]
+        pub static HART: Cell<usize> = Cell::new(0);
+    }
+
+    #[test]
+    fn records_owner() {
+        let m = Mutex::new(());
+        assert_eq!(m.owner(), None);
+        let line = line!() + 1;
+        let g = m.lock();
+        let owner = m.owner().unwrap();
+        assert_eq!(owner.hart, 0);
+        assert_eq!(owner.location.line(), line);
+        assert!(std::format!("{}", owner).starts_with("hart 0 at "));
+        drop(g);
+        assert_eq!(m.owner(), None);
+    }
+
+    #[test]
+    fn panicked_owner_poisons() {
+        let m = Arc::new(Mutex::new(1));
+        let holder = m.clone();
+        thread::spawn(move || {
+            HART.with(|h| h.set(40));
+            let mut g = holder.lock();
+            *g = 2;
+            // Panic without unwinding, like the kernel.
+            core::mem::forget(g);
+            mark_panicked();
+        })
+        .join()
+        .unwrap();
+        assert_eq!(m.owner().map(|o| o.hart), Some(40));
+        let err = m.lock_checked().err().unwrap();
+        assert_eq!(err.owner().hart, 40);
+        assert_eq!(*err.into_inner(), 2);
+        assert!(m.is_poisoned());
+        let poisoned = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(m.lock())));
+        assert!(poisoned.is_err());
+    }
+
+    #[test]
+    fn panicking_hart_can_relock() {
+        // A panic handler which logs while the panicking hart holds the
+        // logger's lock gets the lock back instead of deadlocking.
+        HART.with(|h| h.set(41));
+        let m = Mutex::new(());
+        core::mem::forget(m.lock());
+        mark_panicked();
+        let err = m.lock_checked().err().unwrap();
+        assert_eq!(err.owner().hart, 41);
+        drop(err.into_inner());
+        HART.with(|h| h.set(0));
+    }
+
+    #[test]
+    fn lock_taken_after_panic_isnt_stolen() {
+        // The panic handler's own locks are in use, not abandoned.
+        let m = Arc::new(Mutex::new(0));
+        let holder = m.clone();
+        let (locked, wait) = std::sync::mpsc::channel();
+        let handle = thread::spawn(move || {
+            HART.with(|h| h.set(42));
+            mark_panicked();
+            let mut g = holder.lock();
+            locked.send(()).unwrap();
+            // Give the waiter time to steal it if it's going to.
+            thread::sleep(Duration::from_millis(50));
+            *g = 1;
+        });
+        wait.recv().unwrap();
+        let g = match m.lock_checked() {
+            Ok(g) => g,
+            Err(err) => panic!("stolen, {:?}", err),
+        };
+        assert_eq!(*g, 1);
+        drop(g);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = Mutex::new(1);
+        {
+            let mut g = m.try_lock().unwrap();
+            *g += 1;
+            assert!(m.try_lock().is_none());
+        }
+        assert_eq!(*m.try_lock().unwrap(), 2);
+        unsafe {
+            core::mem::forget(m.lock());
+            m.bust_lock();
+        }
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn fifo_order() {
+        // Queue two waiters behind a held lock and make sure they're served
+        // in the order they arrived.
+        let m = Arc::new(TicketMutex::new(Vec::new()));
+        let held = m.lock();
+        let mut handles = Vec::new();
+        for id in 1..=2 {
+            let waiter = m.clone();
+            handles.push(thread::spawn(move || waiter.lock().push(id)));
+            while m.next.load(Ordering::SeqCst) != id + 1 {
+                thread::yield_now();
+            }
+        }
+        drop(held);
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(*m.lock(), [1, 2]);
+    }
+
+    #[test]
+    fn mutual_exclusion() {
+        const THREADS: usize = 8;
+        const ITERS: usize = 2000;
+        let m = Arc::new(TicketMutex::new(0usize));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let m = m.clone();
+                thread::spawn(move || {
+                    for _ in 0..ITERS {
+                        let before = m.next.load(Ordering::Relaxed);
+                        let mut g = m.lock();
+                        // The count is the number of earlier holders, which
+                        // is our ticket.
+                        assert!(*g >= before);
+                        // Non-atomic increment, so lost updates would show.
+                        let v = *g;
+                        *g = v + 1;
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(*m.lock(), THREADS * ITERS);
+    }
+
+    #[test]
+    fn max_wait() {
+        const THREADS: usize = 8;
+        const ITERS: usize = 1000;
+        let m = Arc::new(TicketMutex::new(()));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let m = m.clone();
+                thread::spawn(move || {
+                    let mut max = Duration::default();
+                    for _ in 0..ITERS {
+                        let start = Instant::now();
+                        let _g = m.lock();
+                        max = core::cmp::max(max, start.elapsed());
+                        // Hold the lock for a moment so there's contention.
+                        for _ in 0..100 {
+                            cpu_relax();
+                        }
+                    }
+                    max
+                })
+            })
+            .collect();
+        let waits: Vec<Duration> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        std::println!("max wait per thread: {:?}", waits);
+        // Every thread got the lock ITERS times, so none starved.
+        assert_eq!(m.next.load(Ordering::SeqCst), THREADS * ITERS);
+    }
+}
@@ -0,0 +1,350 @@
+//! Lock order validation, enabled by the `lockdep` feature.
+//! Locks made at the same `Mutex::new` call site share a class, like the
+//! per-hart locks in an array, so creating locks at runtime doesn't use up
+//! classes. The class id is assigned the first time a lock is taken, and each
+//! hart keeps a stack of the locks it holds. Taking a lock records that every
+//! held class comes before the new one. Two classes taken in both orders can
+//! deadlock two harts, and taking a lock the hart already holds deadlocks it,
+//! so both panic naming where the locks were taken. Only pairs are checked,
+//! longer cycles go unnoticed.
+use crate::{hart_id, has_panicked};
+use core::fmt;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+/// Locks made at call sites past this many aren't checked.
+const MAX_CLASSES: usize = 64;
+/// Locks a hart can hold at once. Locks past this aren't checked.
+const MAX_HELD: usize = 16;
+/// Matches hart::MAX_HARTS in the kernel.
+#[cfg(not(test))]
+const MAX_HARTS: usize = 8;
+
+const UNASSIGNED: usize = 0;
+const UNTRACKED: usize = usize::MAX;
+
+/// A lock's class, keyed by where the lock was made.
+pub(crate) struct LockClass {
+    site: &'static Location<'static>,
+    // The class id plus one, or UNASSIGNED until the lock is first taken.
+    id: AtomicUsize,
+}
+
+impl LockClass {
+    pub(crate) const fn new(site: &'static Location<'static>) -> Self {
+        Self {
+            site,
+            id: AtomicUsize::new(UNASSIGNED),
+        }
+    }
+
+    fn id(&self) -> Option<usize> {
+        let id = match self.id.load(Ordering::Acquire) {
+            UNASSIGNED => {
+                let id = class_for(self.site).map_or(UNTRACKED, |class| class + 1);
+                self.id.store(id, Ordering::Release);
+                id
+            }
+            id => id,
+        };
+        if id == UNTRACKED {
+            None
+        } else {
+            Some(id - 1)
+        }
+    }
+}
+
+/// The call site each class was made at. Sites are compared by value, since
+/// the same site can have several `Location`s.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SITE: AtomicPtr<Location<'static>> = AtomicPtr::new(ptr::null_mut());
+static SITES: [AtomicPtr<Location<'static>>; MAX_CLASSES] = [NO_SITE; MAX_CLASSES];
+
+/// Find the class for `site`, or give it the first free one.
+fn class_for(site: &'static Location<'static>) -> Option<usize> {
+    let new = site as *const Location<'static> as *mut Location<'static>;
+    for (class, slot) in SITES.iter().enumerate() {
+        let mut current = slot.load(Ordering::Acquire);
+        if current.is_null() {
+            // Another hart may fill the slot first, then it's ours only if
+            // it's the same site.
+            current = match slot.compare_exchange(
+                ptr::null_mut(),
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(class),
+                Err(theirs) => theirs,
+            };
+        }
+        if unsafe { *current == *site } {
+            return Some(class);
+        }
+    }
+    None
+}
+
+/// Bit b of `ORDER[a]` is set once class b has been taken while holding a.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ORDER: AtomicU64 = AtomicU64::new(0);
+static ORDER: [AtomicU64; MAX_CLASSES] = [NO_ORDER; MAX_CLASSES];
+
+#[derive(Clone, Copy)]
+struct Held {
+    class: usize,
+    location: &'static Location<'static>,
+}
+
+struct HeldStack {
+    locks: [Option<Held>; MAX_HELD],
+    depth: usize,
+}
+
+impl HeldStack {
+    const fn new() -> Self {
+        Self {
+            locks: [None; MAX_HELD],
+            depth: 0,
+        }
+    }
+
+    fn held(&self) -> impl Iterator<Item = &Held> {
+        self.locks[..self.depth].iter().flatten()
+    }
+
+    fn push(&mut self, held: Held) {
+        if self.depth < MAX_HELD {
+            self.locks[self.depth] = Some(held);
+            self.depth += 1;
+        }
+    }
+
+    /// Locks needn't be released in the order they were taken, so remove
+    /// the newest entry for the class wherever it is.
+    fn remove(&mut self, class: usize) {
+        let depth = self.depth;
+        if let Some(i) = self.locks[..depth]
+            .iter()
+            .rposition(|h| h.map(|h| h.class) == Some(class))
+        {
+            self.locks.copy_within(i + 1..depth, i);
+            self.locks[depth - 1] = None;
+            self.depth -= 1;
+        }
+    }
+}
+
+/// Each hart only touches its own stack.
+#[cfg(not(test))]
+struct PerHart([core::cell::UnsafeCell<HeldStack>; MAX_HARTS]);
+
+#[cfg(not(test))]
+unsafe impl Sync for PerHart {}
+
+#[cfg(not(test))]
+#[allow(clippy::declare_interior_mutable_const)]
+const NOTHING_HELD: core::cell::UnsafeCell<HeldStack> =
+    core::cell::UnsafeCell::new(HeldStack::new());
+#[cfg(not(test))]
+static HELD: PerHart = PerHart([NOTHING_HELD; MAX_HARTS]);
+
+#[cfg(not(test))]
+fn with_held<R>(f: impl FnOnce(&mut HeldStack) -> R) -> Option<R> {
+    use crate::{IrqControl, Sie};
+    let hart = hart_id();
+    if hart >= MAX_HARTS {
+        return None;
+    }
+    // A trap handler taking locks mustn't see the stack half updated.
+    let was_enabled = Sie::disable();
+    let ret = f(unsafe { &mut *HELD.0[hart].get() });
+    Sie::restore(was_enabled);
+    Some(ret)
+}
+
+#[cfg(test)]
+std::thread_local! {
+    // Test threads all claim to be hart 0, so give each its own stack.
+    #[allow(clippy::missing_const_for_thread_local)]
+    static TEST_HELD: core::cell::RefCell<HeldStack> =
+        core::cell::RefCell::new(HeldStack::new());
+}
+
+#[cfg(test)]
+fn with_held<R>(f: impl FnOnce(&mut HeldStack) -> R) -> Option<R> {
+    Some(TEST_HELD.with(|held| f(&mut held.borrow_mut())))
+}
+
+enum Violation {
+    Recursive {
+        taking: &'static Location<'static>,
+        held: &'static Location<'static>,
+    },
+    Inversion {
+        taking: &'static Location<'static>,
+        held: &'static Location<'static>,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Recursive { taking, held } => write!(
+                f,
+                "recursive locking at {}, already held from {}",
+                taking, held
+            ),
+            Violation::Inversion { taking, held } => write!(
+                f,
+                "lock order inversion at {} while holding the lock taken at {}, \
+                 they have been taken in the other order before",
+                taking, held
+            ),
+        }
+    }
+}
+
+/// Check a lock is safe to take given the locks this hart holds, then record
+/// that it is held. Call before spinning, so a deadlock is reported instead
+/// of hanging.
+pub(crate) fn acquire(class: &LockClass, taking: &'static Location<'static>) {
+    let class = match class.id() {
+        Some(class) => class,
+        None => return,
+    };
+    // Whatever the panic handler does with locks is fair game.
+    if has_panicked(hart_id()) {
+        return;
+    }
+    let violation = with_held(|stack| {
+        for held in stack.held() {
+            if held.class == class {
+                return Some(Violation::Recursive {
+                    taking,
+                    held: held.location,
+                });
+            }
+            if ORDER[class].load(Ordering::Relaxed) & (1 << held.class) != 0 {
+                return Some(Violation::Inversion {
+                    taking,
+                    held: held.location,
+                });
+            }
+        }
+        for held in stack.held() {
+            ORDER[held.class].fetch_or(1 << class, Ordering::Relaxed);
+        }
+        stack.push(Held {
+            class,
+            location: taking,
+        });
+        None
+    });
+    if let Some(Some(violation)) = violation {
+        panic!("lockdep: {}", violation);
+    }
+}
+
+/// Record a lock taken by try_lock. Trying can't deadlock, so there's nothing
+/// to check, but later locks are ordered after it.
+pub(crate) fn acquired(class: &LockClass, location: &'static Location<'static>) {
+    if let Some(class) = class.id() {
+        with_held(|stack| stack.push(Held { class, location }));
+    }
+}
+
+pub(crate) fn release(class: &LockClass) {
+    if let Some(class) = class.id() {
+        with_held(|stack| stack.remove(class));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mutex;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::string::String;
+    use std::vec::Vec;
+
+    fn violation(f: impl FnOnce()) -> String {
+        let err = catch_unwind(AssertUnwindSafe(f)).expect_err("no violation");
+        err.downcast::<String>().map(|s| *s).unwrap()
+    }
+
+    fn depth() -> usize {
+        super::with_held(|stack| stack.depth).unwrap()
+    }
+
+    #[test]
+    fn inversion() {
+        let a = Mutex::new(());
+        let b = Mutex::new(());
+        {
+            let _a = a.lock();
+            let _b = b.lock();
+        }
+        // The same order again is fine.
+        {
+            let _a = a.lock();
+            let _b = b.lock();
+        }
+        let _b = b.lock();
+        let msg = violation(|| drop(a.lock()));
+        assert!(msg.starts_with("lockdep: lock order inversion"), "{}", msg);
+        assert!(msg.contains("lockdep.rs"));
+    }
+
+    #[test]
+    fn recursive() {
+        let a = Mutex::new(());
+        let _a = a.lock();
+        let msg = violation(|| drop(a.lock()));
+        assert!(msg.starts_with("lockdep: recursive locking"), "{}", msg);
+    }
+
+    #[test]
+    fn try_lock_isnt_ordered() {
+        let a = Mutex::new(());
+        let b = Mutex::new(());
+        {
+            let _a = a.lock();
+            let _b = b.lock();
+        }
+        let _b = b.lock();
+        assert!(a.try_lock().is_some());
+    }
+
+    #[test]
+    fn classes_are_per_call_site() {
+        let locks: Vec<Mutex<()>> = (0..2 * super::MAX_CLASSES)
+            .map(|_| Mutex::new(()))
+            .collect();
+        let class = locks[0].class.id();
+        assert!(class.is_some());
+        assert!(locks.iter().all(|lock| lock.class.id() == class));
+        // So holding two of them looks like taking the same lock twice.
+        let _first = locks[0].lock();
+        let msg = violation(|| drop(locks[1].lock()));
+        assert!(msg.starts_with("lockdep: recursive locking"), "{}", msg);
+    }
+
+    #[test]
+    fn out_of_order_release() {
+        let a = Mutex::new(());
+        let b = Mutex::new(());
+        let c = Mutex::new(());
+        let ga = a.lock();
+        let gb = b.lock();
+        drop(ga);
+        assert_eq!(depth(), 1);
+        // b is still held, so c is ordered after it but not after a.
+        let gc = c.lock();
+        drop((gb, gc));
+        assert_eq!(depth(), 0);
+        let _c = c.lock();
+        let _a = a.lock();
+    }
+}
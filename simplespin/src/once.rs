@@ -0,0 +1,178 @@
+/// One-time initialization.
+/// `Once` holds a value which is set exactly once, for globals which can only
+/// be built at runtime, like drivers for devices found in the device tree.
+/// `Lazy` builds its value on first use.
+use crate::cpu_relax;
+use core::cell::{Cell, UnsafeCell};
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+pub struct Once<T = ()> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Run `f` to set the value if nobody has yet. Other callers wait for the
+    /// first to finish. Returns the value.
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        if self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+            .is_ok()
+        {
+            // A panic in f leaves the state RUNNING, so later callers spin.
+            // We don't unwind, so that's no worse than the panic itself.
+            unsafe { (*self.value.get()).as_mut_ptr().write(f()) };
+            self.state.store(COMPLETE, Ordering::Release);
+        }
+        self.wait()
+    }
+
+    /// Wait for another caller to finish setting the value.
+    pub fn wait(&self) -> &T {
+        loop {
+            if let Some(value) = self.get() {
+                return value;
+            }
+            cpu_relax();
+        }
+    }
+
+    /// The value, if it has been set.
+    pub fn get(&self) -> Option<&T> {
+        if self.is_completed() {
+            Some(unsafe { &*(*self.value.get()).as_ptr() })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { core::ptr::drop_in_place((*self.value.get()).as_mut_ptr()) };
+        }
+    }
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+/// A value built by `init` the first time it is used.
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: Cell<Option<F>>,
+}
+
+impl<T, F> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: Once::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Build the value now if it hasn't been.
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| match this.init.take() {
+            Some(init) => init(),
+            None => unreachable!("Lazy initialized twice"),
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        Self::force(self)
+    }
+}
+
+// init is only taken by the one caller which wins the Once.
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn once_runs_once() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let once = Arc::new(Once::new());
+        assert!(once.get().is_none());
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let once = once.clone();
+                thread::spawn(move || {
+                    *once.call_once(|| {
+                        CALLS.fetch_add(1, Ordering::SeqCst);
+                        thread::yield_now();
+                        i
+                    })
+                })
+            })
+            .collect();
+        let values: Vec<usize> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        // Everyone saw the winner's value.
+        assert!(values.iter().all(|v| *v == values[0]));
+        assert_eq!(once.get(), Some(&values[0]));
+    }
+
+    #[test]
+    fn lazy_static() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static TABLE: Lazy<[usize; 4]> = Lazy::new(|| {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            [1, 2, 3, 4]
+        });
+        let handles: Vec<_> = (0..8)
+            .map(|_| thread::spawn(|| TABLE.iter().sum::<usize>()))
+            .collect();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), 10);
+        }
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn once_drops_value() {
+        let value = Arc::new(());
+        {
+            let once = Once::new();
+            once.call_once(|| value.clone());
+            assert_eq!(Arc::strong_count(&value), 2);
+        }
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+}
@@ -0,0 +1,266 @@
+/// A lock-free byte ring buffer. Any number of harts, or trap handlers, may
+/// push at once, while consumers pop. Pushing never waits on a consumer: when
+/// the ring is full the bytes which don't fit are left to the caller.
+use crate::{cpu_relax, IrqControl, Sie};
+use core::cell::UnsafeCell;
+use core::cmp::min;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// `N` must be a power of two. The positions count bytes ever pushed and
+/// popped, and wrap around on the buffer.
+pub struct ByteRing<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    // Bytes up to here have been claimed by producers.
+    reserved: AtomicUsize,
+    // Bytes up to here have been written and may be popped.
+    committed: AtomicUsize,
+    // Bytes up to here have been popped.
+    popped: AtomicUsize,
+}
+
+impl<const N: usize> ByteRing<N> {
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two());
+        Self {
+            buf: UnsafeCell::new([0; N]),
+            reserved: AtomicUsize::new(0),
+            committed: AtomicUsize::new(0),
+            popped: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Bytes ready to pop.
+    pub fn len(&self) -> usize {
+        self.committed
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.popped.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Push as many of `bytes` as fit, all in one piece, returning how many.
+    pub fn push(&self, bytes: &[u8]) -> usize {
+        self.push_inner(bytes, true)
+    }
+
+    /// Push all of `bytes` in one piece, or none if they don't fit.
+    pub fn push_all(&self, bytes: &[u8]) -> bool {
+        self.push_inner(bytes, false) == bytes.len()
+    }
+
+    fn push_inner(&self, bytes: &[u8], partial: bool) -> usize {
+        // A trap handler pushing on this hart would wait forever for our
+        // commit, so interrupts stay off between reserving and committing.
+        let was_enabled = Sie::disable();
+        let (start, len) = loop {
+            // Load popped first. Reserved never falls behind it, so used
+            // can't wrap, but consumers may pop in between and leave used
+            // over N, then look again.
+            let popped = self.popped.load(Ordering::Acquire);
+            let start = self.reserved.load(Ordering::Relaxed);
+            let free = match N.checked_sub(start.wrapping_sub(popped)) {
+                Some(free) => free,
+                None => continue,
+            };
+            let len = min(bytes.len(), free);
+            if len == 0 || (!partial && len < bytes.len()) {
+                Sie::restore(was_enabled);
+                return 0;
+            }
+            if self
+                .reserved
+                .compare_exchange_weak(
+                    start,
+                    start.wrapping_add(len),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                break (start, len);
+            }
+        };
+        for (i, byte) in bytes[..len].iter().enumerate() {
+            unsafe { ptr::write_volatile(self.slot(start.wrapping_add(i)), *byte) };
+        }
+        // Commits are in order, so wait for producers which reserved before
+        // us. They have interrupts off too, so they won't be long.
+        while self.committed.load(Ordering::Relaxed) != start {
+            cpu_relax();
+        }
+        self.committed.store(start.wrapping_add(len), Ordering::Release);
+        Sie::restore(was_enabled);
+        len
+    }
+
+    /// Pop up to `out.len()` bytes into `out`, returning how many.
+    pub fn pop(&self, out: &mut [u8]) -> usize {
+        loop {
+            let start = self.popped.load(Ordering::Acquire);
+            let ready = self.committed.load(Ordering::Acquire).wrapping_sub(start);
+            let len = min(out.len(), ready);
+            if len == 0 {
+                return 0;
+            }
+            for (i, byte) in out[..len].iter_mut().enumerate() {
+                *byte = unsafe { ptr::read_volatile(self.slot(start.wrapping_add(i))) };
+            }
+            // Producers can't reuse the space until we move popped on, so
+            // if nobody else popped meanwhile what we read is good.
+            if self
+                .popped
+                .compare_exchange(
+                    start,
+                    start.wrapping_add(len),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return len;
+            }
+        }
+    }
+
+    fn slot(&self, pos: usize) -> *mut u8 {
+        unsafe { (self.buf.get() as *mut u8).add(pos & (N - 1)) }
+    }
+}
+
+impl<const N: usize> Default for ByteRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<const N: usize> Sync for ByteRing<N> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn push_pop_wraps() {
+        let ring: ByteRing<8> = ByteRing::new();
+        let mut out = [0; 8];
+        assert_eq!(ring.push(b"hello"), 5);
+        assert_eq!(ring.pop(&mut out[..3]), 3);
+        assert_eq!(&out[..3], b"hel");
+        // Wraps around the end of the buffer, and only 6 bytes fit.
+        assert_eq!(ring.push(b"world!!"), 6);
+        assert_eq!(ring.len(), 8);
+        assert_eq!(ring.push(b"x"), 0);
+        assert!(ring.pop(&mut out[..1]) == 1 && !ring.push_all(b"xy"));
+        assert_eq!(ring.pop(&mut out), 7);
+        assert_eq!(&out[..7], b"oworld!");
+        assert!(ring.is_empty());
+        assert_eq!(ring.pop(&mut out), 0);
+    }
+
+    #[test]
+    fn producers_dont_interleave() {
+        // Each producer pushes records of its own id byte. Records must come
+        // out whole and each producer's in order.
+        const PRODUCERS: u8 = 4;
+        const RECORDS: usize = 500;
+        const RECORD: usize = 7;
+        let ring: Arc<ByteRing<64>> = Arc::new(ByteRing::new());
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|id| {
+                let ring = ring.clone();
+                thread::spawn(move || {
+                    let mut record = [id; RECORD];
+                    for seq in 0..RECORDS {
+                        record[1] = seq as u8;
+                        while !ring.push_all(&record) {
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let mut got = Vec::new();
+        let mut out = [0; RECORD];
+        while got.len() < PRODUCERS as usize * RECORDS * RECORD {
+            let n = ring.pop(&mut out);
+            got.extend_from_slice(&out[..n]);
+            thread::yield_now();
+        }
+        for p in producers {
+            p.join().unwrap();
+        }
+        let mut next = [0usize; PRODUCERS as usize];
+        for record in got.chunks(RECORD) {
+            let id = record[0];
+            assert!(record[2..].iter().all(|b| *b == id), "torn {:?}", record);
+            assert_eq!(record[1], next[id as usize] as u8);
+            next[id as usize] += 1;
+        }
+        assert!(next.iter().all(|n| *n == RECORDS));
+    }
+
+    #[test]
+    fn many_producers_and_consumers() {
+        // A small ring so producers keep racing consumers for the space.
+        const PRODUCERS: u8 = 8;
+        const CONSUMERS: usize = 2;
+        const RECORDS: usize = 300;
+        const RECORD: usize = 3;
+        const TOTAL: usize = PRODUCERS as usize * RECORDS * RECORD;
+        let ring: Arc<ByteRing<8>> = Arc::new(ByteRing::new());
+        let popped = Arc::new(AtomicUsize::new(0));
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|id| {
+                let ring = ring.clone();
+                thread::spawn(move || {
+                    for _ in 0..RECORDS {
+                        while !ring.push_all(&[id; RECORD]) {
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..CONSUMERS)
+            .map(|_| {
+                let ring = ring.clone();
+                let popped = popped.clone();
+                thread::spawn(move || {
+                    let mut counts = [0usize; PRODUCERS as usize];
+                    let mut out = [0; 5];
+                    while popped.load(Ordering::Relaxed) < TOTAL {
+                        let n = ring.pop(&mut out);
+                        for byte in &out[..n] {
+                            counts[*byte as usize] += 1;
+                        }
+                        popped.fetch_add(n, Ordering::Relaxed);
+                    }
+                    counts
+                })
+            })
+            .collect();
+        for p in producers {
+            p.join().unwrap();
+        }
+        let mut counts = [0usize; PRODUCERS as usize];
+        for c in consumers {
+            for (total, n) in counts.iter_mut().zip(c.join().unwrap().iter()) {
+                *total += n;
+            }
+        }
+        // Every byte came out exactly once.
+        assert_eq!(counts, [RECORDS * RECORD; PRODUCERS as usize]);
+        assert!(ring.is_empty());
+    }
+}
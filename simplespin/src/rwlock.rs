@@ -0,0 +1,198 @@
+/// A spinning reader-writer lock for read-mostly data.
+/// Writers have preference: once a writer is waiting new readers hold off, so
+/// a steady stream of readers can't starve it.
+use crate::cpu_relax;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const WRITER: usize = 1;
+const WRITER_WAITING: usize = 1 << 1;
+// The rest of the state is the reader count.
+const READER: usize = 1 << 2;
+
+#[derive(Default)]
+pub struct RwLock<T> {
+    state: AtomicUsize,
+    item: UnsafeCell<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(item: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            item: UnsafeCell::new(item),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            cpu_relax();
+        }
+    }
+
+    /// Take a read lock unless a writer holds or is waiting for the lock.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | WRITER_WAITING) != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange_weak(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockReadGuard { lock: self })
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & !WRITER_WAITING == 0 {
+                // Free. Taking it clears the waiting bit, other waiting
+                // writers will set it again.
+                if self
+                    .state
+                    .compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return RwLockWriteGuard { lock: self };
+                }
+            } else if state & WRITER_WAITING == 0 {
+                self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+            cpu_relax();
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & !WRITER_WAITING != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockWriteGuard { lock: self })
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.item.get() }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.item.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.item.get() }
+    }
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: Send> Send for RwLock<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn readers_share_writers_exclude() {
+        let lock = RwLock::new(1);
+        let a = lock.read();
+        let b = lock.read();
+        assert_eq!(*a + *b, 2);
+        assert!(lock.try_write().is_none());
+        drop((a, b));
+        let mut w = lock.write();
+        *w = 5;
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+        drop(w);
+        assert_eq!(*lock.read(), 5);
+    }
+
+    #[test]
+    fn waiting_writer_blocks_new_readers() {
+        let lock = Arc::new(RwLock::new(0));
+        let reader = lock.read();
+        let writer = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.write() += 1)
+        };
+        while lock.state.load(Ordering::SeqCst) & WRITER_WAITING == 0 {
+            thread::yield_now();
+        }
+        assert!(lock.try_read().is_none());
+        drop(reader);
+        writer.join().unwrap();
+        assert_eq!(*lock.read(), 1);
+        assert_eq!(lock.state.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn stress() {
+        // Writers keep both halves equal, readers must never see them differ.
+        const WRITERS: usize = 2;
+        const READERS: usize = 6;
+        const ITERS: usize = 500;
+        let lock = Arc::new(RwLock::new((0usize, 0usize)));
+        let mut handles = Vec::new();
+        for _ in 0..WRITERS {
+            let lock = lock.clone();
+            handles.push(thread::spawn(move || {
+                for _ in 0..ITERS {
+                    let mut w = lock.write();
+                    w.0 += 1;
+                    thread::yield_now();
+                    w.1 += 1;
+                }
+            }));
+        }
+        for _ in 0..READERS {
+            let lock = lock.clone();
+            handles.push(thread::spawn(move || {
+                for _ in 0..ITERS {
+                    let r = lock.read();
+                    assert_eq!(r.0, r.1);
+                }
+            }));
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(*lock.read(), (WRITERS * ITERS, WRITERS * ITERS));
+    }
+}
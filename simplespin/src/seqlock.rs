@@ -0,0 +1,137 @@
+/// A sequence lock for small `Copy` data which is read far more often than
+/// written, like the time. Readers never block writers and never write
+/// shared memory, they retry if a write happened while they were reading.
+use crate::{cpu_relax, IrqControl, Sie};
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+#[derive(Default)]
+pub struct SeqLock<T: Copy> {
+    // Odd while a write is in progress.
+    seq: AtomicUsize,
+    item: UnsafeCell<T>,
+}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(item: T) -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            item: UnsafeCell::new(item),
+        }
+    }
+
+    pub fn read(&self) -> T {
+        loop {
+            let before = self.seq.load(Ordering::Acquire);
+            if before & 1 == 0 {
+                // This may race a writer and see a torn value, which we throw
+                // away below.
+                let item = unsafe { ptr::read_volatile(self.item.get()) };
+                fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == before {
+                    return item;
+                }
+            }
+            cpu_relax();
+        }
+    }
+
+    pub fn write(&self, item: T) {
+        self.update(|old| *old = item);
+    }
+
+    /// Update the value in place. Writers exclude each other, and interrupts
+    /// are off meanwhile so a trap handler reading on this hart can't spin on
+    /// our half finished write.
+    pub fn update(&self, f: impl FnOnce(&mut T)) {
+        let was_enabled = Sie::disable();
+        let mut seq = self.seq.load(Ordering::Relaxed);
+        loop {
+            if seq & 1 == 0 {
+                match self.seq.compare_exchange_weak(
+                    seq,
+                    seq + 1,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(current) => seq = current,
+                }
+            } else {
+                cpu_relax();
+                seq = self.seq.load(Ordering::Relaxed);
+            }
+        }
+        fence(Ordering::Release);
+        let mut item = unsafe { ptr::read_volatile(self.item.get()) };
+        f(&mut item);
+        unsafe { ptr::write_volatile(self.item.get(), item) };
+        self.seq.store(seq + 2, Ordering::Release);
+        Sie::restore(was_enabled);
+    }
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn read_write() {
+        let lock = SeqLock::new((1, 2));
+        assert_eq!(lock.read(), (1, 2));
+        lock.write((3, 4));
+        lock.update(|v| v.0 += 1);
+        assert_eq!(lock.read(), (4, 4));
+        assert_eq!(lock.seq.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn readers_never_see_torn_writes() {
+        // Writers keep every word equal, readers check they are.
+        let lock = Arc::new(SeqLock::new([0usize; 8]));
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let lock = lock.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut reads = 0;
+                    while !done.load(Ordering::Relaxed) || reads == 0 {
+                        let v = lock.read();
+                        assert!(v.iter().all(|w| *w == v[0]), "torn read {:?}", v);
+                        reads += 1;
+                    }
+                })
+            })
+            .collect();
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..2000 {
+                        lock.update(|v| {
+                            for w in v.iter_mut() {
+                                *w += 1;
+                            }
+                        });
+                    }
+                })
+            })
+            .collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        for r in readers {
+            r.join().unwrap();
+        }
+        assert_eq!(lock.read(), [4000; 8]);
+    }
+}
@@ -0,0 +1,401 @@
+/// Buddy-system allocator for physical page frames.
+/// Free blocks live on per-order doubly linked lists which are stored in the
+/// free frames themselves, so the allocator never touches the kernel heap.
+/// The only other state is one byte per frame holding the order of the free
+/// block which starts there, which is how we find free buddies to coalesce.
+///
+/// Frames are accessed through their physical address. That works while
+/// paging is off, but will need to go through the physical memory window once
+/// the MMU is enabled.
+use crate::constants::PAGE_SIZE;
+use crate::math::{align_down_by, is_aligned_by};
+use crate::range::Range;
+use core::ptr;
+
+/// Blocks come in orders 0 through MAX_ORDER - 1, from 4 KiB pages up to
+/// 1 GiB huge pages.
+pub const MAX_ORDER: usize = 19;
+const MAX_BLOCK_SIZE: usize = PAGE_SIZE << (MAX_ORDER - 1);
+// Marks frames which aren't the first frame of a free block.
+const NOT_FREE: u8 = 0xff;
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+pub struct BuddyAllocator {
+    // Address of frame 0. Aligned to MAX_BLOCK_SIZE so that blocks of every
+    // order are naturally aligned.
+    base: usize,
+    frames: usize,
+    orders: *mut u8,
+    free_lists: [*mut FreeBlock; MAX_ORDER],
+    free: usize,
+}
+
+// The pointers are into physical memory the allocator owns, not to anything
+// tied to one hart.
+unsafe impl Send for BuddyAllocator {}
+
+/// Size in bytes of a block of the given order.
+pub fn order_size(order: usize) -> usize {
+    PAGE_SIZE << order
+}
+
+/// The smallest order whose blocks can hold `size` bytes.
+pub fn order_for(size: usize) -> usize {
+    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    pages.next_power_of_two().trailing_zeros() as usize
+}
+
+impl BuddyAllocator {
+    pub const fn empty() -> Self {
+        Self {
+            base: 0,
+            frames: 0,
+            orders: ptr::null_mut(),
+            free_lists: [ptr::null_mut(); MAX_ORDER],
+            free: 0,
+        }
+    }
+
+    /// Bytes of metadata needed to manage the frames in `span`.
+    pub fn metadata_size(span: &Range) -> usize {
+        (span.end - align_down_by(span.start, MAX_BLOCK_SIZE)) / PAGE_SIZE
+    }
+
+    /// Set up an allocator covering `span`. Nothing is free until it is
+    /// handed over with `free_range`.
+    /// # Safety
+    /// `metadata` must point to `metadata_size(span)` bytes of memory which is
+    /// not used for anything else for the lifetime of the allocator.
+    pub unsafe fn init(&mut self, span: &Range, metadata: *mut u8) {
+        assert!(is_aligned_by(span.end, PAGE_SIZE));
+        self.base = align_down_by(span.start, MAX_BLOCK_SIZE);
+        self.frames = Self::metadata_size(span);
+        self.orders = metadata;
+        ptr::write_bytes(self.orders, NOT_FREE, self.frames);
+        self.free_lists = [ptr::null_mut(); MAX_ORDER];
+        self.free = 0;
+    }
+
+    /// Bytes currently free.
+    pub fn free_bytes(&self) -> usize {
+        self.free
+    }
+
+    /// Number of free blocks of the given order. This walks the free list.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut n = 0;
+        let mut cur = self.free_lists[order];
+        while !cur.is_null() {
+            n += 1;
+            cur = unsafe { (*cur).next };
+        }
+        n
+    }
+
+    fn frame(&self, addr: usize) -> usize {
+        assert!(addr >= self.base);
+        let frame = (addr - self.base) / PAGE_SIZE;
+        assert!(frame < self.frames);
+        frame
+    }
+
+    unsafe fn order_at(&self, addr: usize) -> u8 {
+        *self.orders.add(self.frame(addr))
+    }
+
+    unsafe fn set_order_at(&mut self, addr: usize, order: u8) {
+        *self.orders.add(self.frame(addr)) = order;
+    }
+
+    unsafe fn push(&mut self, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        let head = self.free_lists[order];
+        (*block).next = head;
+        (*block).prev = ptr::null_mut();
+        if !head.is_null() {
+            (*head).prev = block;
+        }
+        self.free_lists[order] = block;
+        self.set_order_at(addr, order as u8);
+        self.free += order_size(order);
+    }
+
+    unsafe fn unlink(&mut self, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        assert_eq!(self.order_at(addr), order as u8);
+        if (*block).prev.is_null() {
+            self.free_lists[order] = (*block).next;
+        } else {
+            (*(*block).prev).next = (*block).next;
+        }
+        if !(*block).next.is_null() {
+            (*(*block).next).prev = (*block).prev;
+        }
+        self.set_order_at(addr, NOT_FREE);
+        self.free -= order_size(order);
+    }
+
+    /// Split the free-list block at `addr` down to `order`, returning the
+    /// upper halves to the free lists. The lower half keeps the alignment of
+    /// the original block.
+    unsafe fn take(&mut self, addr: usize, mut from: usize, order: usize) -> usize {
+        self.unlink(addr, from);
+        while from > order {
+            from -= 1;
+            self.push(addr + order_size(from), from);
+        }
+        addr
+    }
+
+    /// Allocate a block of the given order. Returns its address.
+    pub fn alloc(&mut self, order: usize) -> Option<usize> {
+        for from in order..MAX_ORDER {
+            let block = self.free_lists[from];
+            if !block.is_null() {
+                return Some(unsafe { self.take(block as usize, from, order) });
+            }
+        }
+        None
+    }
+
+    /// Allocate a block of the given order which starts on an `align`
+    /// boundary and ends at or below `max_addr`. Unlike `alloc` this may need
+    /// to walk the free lists when `max_addr` excludes part of memory.
+    pub fn alloc_constrained(&mut self, order: usize, align: usize, max_addr: usize) -> Option<usize> {
+        // Blocks are naturally aligned, so any block at least as large as the
+        // alignment will do, and so will a smaller one which happens to start
+        // on the boundary.
+        for from in order..MAX_ORDER {
+            let mut cur = self.free_lists[from];
+            while !cur.is_null() {
+                let addr = cur as usize;
+                if is_aligned_by(addr, align) && addr + order_size(order) <= max_addr {
+                    return Some(unsafe { self.take(addr, from, order) });
+                }
+                cur = unsafe { (*cur).next };
+            }
+        }
+        None
+    }
+
+    /// Free a block of the given order, merging it with its buddies.
+    /// # Safety
+    /// The block must have come from this allocator and must not be in use.
+    pub unsafe fn free(&mut self, mut addr: usize, mut order: usize) {
+        assert!(is_aligned_by(addr, order_size(order)));
+        assert_eq!(self.order_at(addr), NOT_FREE, "double free of {:#x}", addr);
+        while order < MAX_ORDER - 1 {
+            let buddy = self.base + ((addr - self.base) ^ order_size(order));
+            if buddy + order_size(order) > self.base + self.frames * PAGE_SIZE
+                || self.order_at(buddy) != order as u8
+            {
+                break;
+            }
+            self.unlink(buddy, order);
+            addr = core::cmp::min(addr, buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    /// Free every frame in a page aligned range, in the largest blocks that
+    /// fit.
+    /// # Safety
+    /// The range must be unused RAM inside the span given to `init`.
+    pub unsafe fn free_range(&mut self, rg: &Range) {
+        assert!(is_aligned_by(rg.start, PAGE_SIZE) && is_aligned_by(rg.end, PAGE_SIZE));
+        let mut start = rg.start;
+        while start < rg.end {
+            let mut order = MAX_ORDER - 1;
+            while !is_aligned_by(start, order_size(order)) || start + order_size(order) > rg.end {
+                order -= 1;
+            }
+            self.free(start, order);
+            start += order_size(order);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::constants::{LARGE_PAGE_SIZE, MB};
+    use crate::math::align_up_by;
+    use crate::range::RangeSet;
+    use std::vec::Vec;
+
+    /// Page aligned host memory standing in for physical RAM.
+    struct Ram {
+        _backing: Vec<u8>,
+        _metadata: Vec<u8>,
+        usable: Range,
+        buddy: BuddyAllocator,
+    }
+
+    impl Ram {
+        fn new(len: usize) -> Self {
+            let mut backing = vec![0u8; len + LARGE_PAGE_SIZE];
+            let start = align_up_by(backing.as_mut_ptr() as usize, LARGE_PAGE_SIZE);
+            let usable = Range::new(start, start + len);
+            let mut metadata = vec![0u8; BuddyAllocator::metadata_size(&usable)];
+            let mut buddy = BuddyAllocator::empty();
+            unsafe {
+                buddy.init(&usable, metadata.as_mut_ptr());
+                buddy.free_range(&usable);
+            }
+            Self { _backing: backing, _metadata: metadata, usable, buddy }
+        }
+    }
+
+    #[test]
+    fn order_math() {
+        assert_eq!(order_for(1), 0);
+        assert_eq!(order_for(PAGE_SIZE), 0);
+        assert_eq!(order_for(PAGE_SIZE + 1), 1);
+        assert_eq!(order_for(3 * PAGE_SIZE), 2);
+        assert_eq!(order_for(LARGE_PAGE_SIZE), 9);
+        assert_eq!(order_size(MAX_ORDER - 1), crate::constants::HUGE_PAGE_SIZE);
+    }
+
+    #[test]
+    fn split_and_coalesce() {
+        let mut ram = Ram::new(LARGE_PAGE_SIZE);
+        let len = ram.usable.len();
+        assert_eq!(ram.buddy.free_bytes(), len);
+        assert_eq!(ram.buddy.free_blocks(9), 1);
+
+        let a = ram.buddy.alloc(0).unwrap();
+        assert_eq!(a, ram.usable.start);
+        // Splitting a 2M block down to 4K leaves one free block of each
+        // smaller order.
+        for order in 0..9 {
+            assert_eq!(ram.buddy.free_blocks(order), 1);
+        }
+        let b = ram.buddy.alloc(0).unwrap();
+        assert_eq!(b, a + PAGE_SIZE);
+        let c = ram.buddy.alloc(3).unwrap();
+        assert!(is_aligned_by(c, order_size(3)));
+        assert_eq!(ram.buddy.free_bytes(), len - 2 * PAGE_SIZE - order_size(3));
+
+        unsafe {
+            ram.buddy.free(a, 0);
+            ram.buddy.free(c, 3);
+            ram.buddy.free(b, 0);
+        }
+        assert_eq!(ram.buddy.free_bytes(), len);
+        assert_eq!(ram.buddy.free_blocks(9), 1);
+        for order in 0..9 {
+            assert_eq!(ram.buddy.free_blocks(order), 0);
+        }
+    }
+
+    #[test]
+    fn exhaust_and_refill() {
+        let mut ram = Ram::new(MB);
+        let mut pages = Vec::new();
+        while let Some(page) = ram.buddy.alloc(0) {
+            pages.push(page);
+        }
+        assert_eq!(pages.len(), MB / PAGE_SIZE);
+        assert_eq!(ram.buddy.free_bytes(), 0);
+        assert_eq!(ram.buddy.alloc(0), None);
+        // Free in an interleaved order so merges happen in both directions.
+        for page in pages.iter().step_by(2).chain(pages.iter().skip(1).step_by(2)) {
+            unsafe { ram.buddy.free(*page, 0) };
+        }
+        assert_eq!(ram.buddy.free_bytes(), MB);
+        assert_eq!(ram.buddy.free_blocks(order_for(MB)), 1);
+    }
+
+    #[test]
+    fn constrained() {
+        let mut ram = Ram::new(4 * MB);
+        let start = ram.usable.start;
+        let low = ram.buddy.alloc_constrained(0, LARGE_PAGE_SIZE, start + MB).unwrap();
+        assert_eq!(low, start);
+        let high = ram.buddy.alloc_constrained(0, LARGE_PAGE_SIZE, usize::MAX).unwrap();
+        assert!(is_aligned_by(high, LARGE_PAGE_SIZE));
+        assert_ne!(high, low);
+        assert_eq!(ram.buddy.alloc_constrained(0, PAGE_SIZE, start), None);
+        assert_eq!(ram.buddy.alloc_constrained(0, 4 * MB, usize::MAX), None);
+    }
+
+    #[test]
+    fn constrained_uses_small_aligned_blocks() {
+        let mut ram = Ram::new(LARGE_PAGE_SIZE);
+        let a = ram.buddy.alloc(0).unwrap();
+        let b = ram.buddy.alloc(0).unwrap();
+        // a can't merge while b is allocated, so the only 2M aligned block
+        // left is a single page.
+        unsafe { ram.buddy.free(a, 0) };
+        assert_eq!(ram.buddy.alloc_constrained(0, LARGE_PAGE_SIZE, usize::MAX), Some(a));
+        assert_eq!(ram.buddy.alloc_constrained(1, LARGE_PAGE_SIZE, usize::MAX), None);
+        unsafe {
+            ram.buddy.free(a, 0);
+            ram.buddy.free(b, 0);
+        }
+        assert_eq!(ram.buddy.free_blocks(9), 1);
+    }
+
+    /// Compare the buddy allocator against `RangeSet::find` on fragmented
+    /// memory. Run with `cargo test -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_buddy_vs_range_set() {
+        use std::time::Instant;
+        const LEN: usize = 64 * MB;
+        const ROUNDS: usize = 2000;
+        let mut ram = Ram::new(LEN);
+        let mut rs = RangeSet::empty();
+        rs.insert(ram.usable.clone());
+
+        // Fragment both by allocating everything and then freeing every other
+        // page, except for the last megabyte which is freed in full. Only
+        // that megabyte can satisfy a two page request.
+        const TAIL: usize = MB / PAGE_SIZE;
+        let mut held = Vec::new();
+        while let Some(page) = ram.buddy.alloc(0) {
+            held.push(page);
+        }
+        let mut rs_held = Vec::new();
+        while let Some(rg) = rs.find(PAGE_SIZE) {
+            rs_held.push(rg.start);
+        }
+        held.sort_unstable();
+        rs_held.sort_unstable();
+        assert_eq!(held, rs_held);
+        let split = held.len() - TAIL;
+        let freed = held[..split].iter().step_by(2).chain(held[split..].iter());
+        for page in freed {
+            unsafe { ram.buddy.free(*page, 0) };
+            rs.insert(Range::new(*page, *page + PAGE_SIZE));
+        }
+
+        let now = Instant::now();
+        for _ in 0..ROUNDS {
+            let block = ram.buddy.alloc(1).unwrap();
+            unsafe { ram.buddy.free(block, 1) };
+        }
+        let buddy_time = now.elapsed();
+
+        let now = Instant::now();
+        for _ in 0..ROUNDS {
+            let rg = rs.find(order_size(1)).unwrap();
+            rs.insert(rg);
+        }
+        let range_set_time = now.elapsed();
+
+        std::println!(
+            "{} alloc/free pairs over {} free fragments: buddy {:?}, RangeSet::find {:?}",
+            ROUNDS,
+            rs.iter().count(),
+            buddy_time,
+            range_set_time
+        );
+    }
+}
@@ -28,6 +28,27 @@ pub fn breakpoint() {
     // If we have something like the Bochs magic breakpoint, put it here.
 }
 
+/// Walk `depth` frames up the stack and return that frame's return address,
+/// or 0 if the stack ends first. Depth 0 is the return address into our
+/// caller. Needs frame pointers: on RISC-V the return
+/// address is saved just below the frame pointer, and the caller's frame
+/// pointer below that.
+#[inline(never)]
+pub fn return_address(depth: usize) -> usize {
+    let mut fp: *const usize;
+    unsafe { asm!("mv $0, s0" : "=r"(fp)); }
+    for _ in 0..depth {
+        if fp.is_null() {
+            return 0;
+        }
+        fp = unsafe { *fp.offset(-2) } as *const usize;
+    }
+    if fp.is_null() {
+        return 0;
+    }
+    unsafe { *fp.offset(-1) }
+}
+
 /*
 #[repr(C)]
 struct StackFrame {
@@ -110,6 +110,29 @@ impl<'dtb> DeviceTree<'dtb> {
         }
     }
 
+    /// Size of the device tree blob in bytes, including the header.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns the physical (start, end) of the initrd if the bootloader
+    /// passed one in the `chosen` node.
+    pub fn initrd(&self) -> Option<(usize, usize)> {
+        let start = self.find_property("chosen", "linux,initrd-start")?.read_addr()?;
+        let end = self.find_property("chosen", "linux,initrd-end")?.read_addr()?;
+        Some((start, end))
+    }
+
+    /// Returns the rate at which the `time` CSR and the CLINT's `mtime` count,
+    /// in Hz, from the `cpus` node.
+    pub fn timebase_frequency(&self) -> Option<u64> {
+        let prop = self.find_property("cpus", "timebase-frequency")?;
+        match prop.bytes.len() {
+            4 => prop.read_u32(0).map(u64::from),
+            _ => prop.read_u64(0),
+        }
+    }
+
     pub fn find_regs(&self, name: &str) -> Option<(usize, usize)> {
         let prop = self.find_property(name, "reg")?;
         let start = prop.read_usize(0)?;
@@ -130,7 +153,10 @@ impl<'dtb> DeviceTree<'dtb> {
             match state {
                 SearchState::Node => match n {
                     DeviceTreeStructure::NodeBegin(node_name) => {
-                        if node_name.starts_with(name) && node_name.as_bytes()[name.len()] == b'@' {
+                        if node_name == name
+                            || (node_name.starts_with(name)
+                                && node_name.as_bytes().get(name.len()) == Some(&b'@'))
+                        {
                             state = SearchState::Propery;
                         }
                     }
@@ -248,6 +274,15 @@ pub struct DeviceTreeNodeProperty<'dtb> {
 }
 
 impl<'dtb> DeviceTreeNodeProperty<'dtb> {
+    fn read_u32(&self, offset: usize) -> Option<u32> {
+        const LEN: usize = mem::size_of::<u32>();
+        if offset + LEN > self.bytes.len() {
+            return None;
+        }
+        let bytes: [u8; LEN] = self.bytes[offset..offset + LEN].try_into().ok()?;
+        Some(u32::from_be_bytes(bytes))
+    }
+
     fn read_u64(&self, offset: usize) -> Option<u64> {
         const LEN: usize = mem::size_of::<u64>();
         if offset + LEN > self.bytes.len() {
@@ -276,6 +311,27 @@ impl<'dtb> DeviceTreeNodeProperty<'dtb> {
         }
     }
 
+    /// Read a property holding a single address, which may be encoded as
+    /// either one or two cells.
+    fn read_addr(&self) -> Option<usize> {
+        match self.bytes.len() {
+            4 => Some(u32::from_be_bytes(self.bytes.try_into().ok()?) as usize),
+            8 => self.read_usize(0),
+            _ => None,
+        }
+    }
+
+    /// Iterate over the (address, size) pairs in a `reg` property. Like
+    /// `find_regs` this assumes two address cells and two size cells.
+    pub fn regs(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        const PAIR: usize = 2 * mem::size_of::<usize>();
+        (0..self.bytes.len() / PAIR).filter_map(move |i| {
+            let start = self.read_usize(i * PAIR)?;
+            let size = self.read_usize(i * PAIR + mem::size_of::<usize>())?;
+            Some((start, size))
+        })
+    }
+
     fn new(bytes: &'dtb [u8], name: &'dtb str) -> Self {
         Self { bytes, name }
     }
@@ -419,5 +475,15 @@ mod tests {
         assert_eq!(prop.read_u64(16), None);
         let uart_regs = dtb.find_regs("uart").unwrap();
         assert_eq!(uart_regs, (0x10000000, 0x100));
+        assert_eq!(dtb.find_regs("clint"), Some((0x2000000, 0x10000)));
+        assert_eq!(dtb.timebase_frequency(), Some(10_000_000));
+
+        let memory = dtb.find_property("memory", "reg").unwrap();
+        let regions: Vec<(usize, usize)> = memory.regs().collect();
+        assert_eq!(regions, [(0x80000000, 0x8000000)]);
+        assert_eq!(dtb.size(), data.len());
+        // This blob has a chosen node but qemu was not given an initrd.
+        assert!(dtb.find_property("chosen", "bootargs").is_some());
+        assert_eq!(dtb.initrd(), None);
     }
 }
@@ -0,0 +1,305 @@
+/// Page frame database.
+/// There is one descriptor per physical frame holding its reference count,
+/// which subsystem owns it, and some flags. Descriptors are only touched with
+/// atomics, so taking and dropping references doesn't need the physical
+/// allocator lock.
+use crate::constants::{KB, PAGE_SIZE};
+use crate::range::Range;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+/// Which subsystem a frame belongs to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Owner {
+    Free = 0,
+    Kernel,
+    PageTable,
+    User,
+    PageCache,
+    Slab,
+}
+
+pub const OWNER_COUNT: usize = 6;
+
+impl Owner {
+    fn from_u8(n: u8) -> Self {
+        match n {
+            1 => Owner::Kernel,
+            2 => Owner::PageTable,
+            3 => Owner::User,
+            4 => Owner::PageCache,
+            5 => Owner::Slab,
+            _ => Owner::Free,
+        }
+    }
+}
+
+/// The frame has been written since it was last cleaned.
+pub const FRAME_DIRTY: u8 = 1 << 0;
+/// The frame is shared copy-on-write.
+pub const FRAME_COW: u8 = 1 << 1;
+/// The frame must never be freed, e.g. the kernel image or the device tree.
+/// References to pinned frames are not counted.
+pub const FRAME_PINNED: u8 = 1 << 2;
+
+/// Per-frame metadata. An all-zero descriptor is a free frame.
+#[derive(Default)]
+#[repr(C)]
+pub struct Frame {
+    refcount: AtomicU32,
+    owner: AtomicU8,
+    flags: AtomicU8,
+}
+
+impl Frame {
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    pub fn owner(&self) -> Owner {
+        Owner::from_u8(self.owner.load(Ordering::Acquire))
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.flags.load(Ordering::Acquire)
+    }
+
+    pub fn set_flags(&self, flags: u8) {
+        self.flags.fetch_or(flags, Ordering::AcqRel);
+    }
+
+    pub fn clear_flags(&self, flags: u8) {
+        self.flags.fetch_and(!flags, Ordering::AcqRel);
+    }
+}
+
+/// Frames in use by each owner, in pages.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct FrameUsage {
+    pub pages: [usize; OWNER_COUNT],
+}
+
+impl FrameUsage {
+    pub fn get(&self, owner: Owner) -> usize {
+        self.pages[owner as usize]
+    }
+
+    /// Log the number of pages used by each owner.
+    pub fn dump(&self) {
+        log!("Frame usage {{");
+        for (i, pages) in self.pages.iter().enumerate().skip(1) {
+            log!("  {:?}: {} KiB", Owner::from_u8(i as u8), pages * PAGE_SIZE / KB);
+        }
+        log!("}}");
+    }
+}
+
+pub struct FrameDatabase {
+    base: AtomicUsize,
+    len: AtomicUsize,
+    frames: AtomicPtr<Frame>,
+}
+
+pub static FRAME_DB: FrameDatabase = FrameDatabase::empty();
+
+impl FrameDatabase {
+    pub const fn empty() -> Self {
+        Self {
+            base: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            frames: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Bytes needed for the descriptors of every frame in `span`.
+    pub fn size_for(span: &Range) -> usize {
+        span.len() / PAGE_SIZE * core::mem::size_of::<Frame>()
+    }
+
+    /// Point the database at its descriptor array and mark every frame free.
+    /// # Safety
+    /// `mem` must point to `size_for(span)` bytes which are not used for
+    /// anything else, and this must happen before any other method is called.
+    pub unsafe fn init(&self, span: &Range, mem: *mut Frame) {
+        let len = span.len() / PAGE_SIZE;
+        ptr::write_bytes(mem, 0, len);
+        self.base.store(span.start, Ordering::Release);
+        self.len.store(len, Ordering::Release);
+        self.frames.store(mem, Ordering::Release);
+    }
+
+    /// The descriptor for the frame containing `addr`, or None if the frame
+    /// isn't RAM we manage, e.g. MMIO.
+    pub fn frame(&self, addr: usize) -> Option<&Frame> {
+        let base = self.base.load(Ordering::Acquire);
+        let frames = self.frames.load(Ordering::Acquire);
+        if frames.is_null() || addr < base {
+            return None;
+        }
+        let i = (addr - base) / PAGE_SIZE;
+        if i >= self.len.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(unsafe { &*frames.add(i) })
+    }
+
+    /// Mark the frames of a freshly allocated range as used by `owner`, with
+    /// one reference each.
+    pub fn claim(&self, rg: &Range, owner: Owner) {
+        for addr in (rg.start..rg.end).step_by(PAGE_SIZE) {
+            if let Some(frame) = self.frame(addr) {
+                let old = frame.refcount.swap(1, Ordering::AcqRel);
+                assert_eq!(old, 0, "frame {:#x} allocated twice", addr);
+                frame.flags.store(0, Ordering::Release);
+                frame.owner.store(owner as u8, Ordering::Release);
+            }
+        }
+    }
+
+    /// Hand the frames of a range to another owner.
+    pub fn set_owner(&self, rg: &Range, owner: Owner) {
+        for addr in (rg.start..rg.end).step_by(PAGE_SIZE) {
+            if let Some(frame) = self.frame(addr) {
+                frame.owner.store(owner as u8, Ordering::Release);
+            }
+        }
+    }
+
+    /// Mark the frames of a reserved range as permanently owned by `owner`.
+    pub fn pin(&self, rg: &Range, owner: Owner) {
+        for addr in (rg.start..rg.end).step_by(PAGE_SIZE) {
+            if let Some(frame) = self.frame(addr) {
+                frame.refcount.store(1, Ordering::Release);
+                frame.flags.store(FRAME_PINNED, Ordering::Release);
+                frame.owner.store(owner as u8, Ordering::Release);
+            }
+        }
+    }
+
+    /// Mark the frames of a range free, once they are back in the allocator.
+    pub fn release(&self, rg: &Range) {
+        for addr in (rg.start..rg.end).step_by(PAGE_SIZE) {
+            if let Some(frame) = self.frame(addr) {
+                frame.owner.store(Owner::Free as u8, Ordering::Release);
+                frame.flags.store(0, Ordering::Release);
+                frame.refcount.store(0, Ordering::Release);
+            }
+        }
+    }
+
+    /// Take another reference to the frame containing `addr`. Like `put`,
+    /// this ignores pinned frames and frames we don't manage.
+    pub fn get(&self, addr: usize) {
+        if let Some(frame) = self.frame(addr) {
+            if frame.flags() & FRAME_PINNED != 0 {
+                return;
+            }
+            let old = frame.refcount.fetch_add(1, Ordering::AcqRel);
+            assert_ne!(old, 0, "get on free frame {:#x}", addr);
+        }
+    }
+
+    /// Drop a reference to the frame containing `addr`. Returns true if that
+    /// was the last reference and the frame should go back to the allocator.
+    /// Pinned frames and frames we don't manage are never freed.
+    pub fn put(&self, addr: usize) -> bool {
+        let frame = match self.frame(addr) {
+            Some(frame) => frame,
+            None => return false,
+        };
+        if frame.flags() & FRAME_PINNED != 0 {
+            return false;
+        }
+        let old = frame.refcount.fetch_sub(1, Ordering::AcqRel);
+        assert_ne!(old, 0, "put on free frame {:#x}", addr);
+        old == 1
+    }
+
+    /// Count the frames held by each owner.
+    pub fn usage(&self) -> FrameUsage {
+        let mut usage = FrameUsage::default();
+        let base = self.base.load(Ordering::Acquire);
+        for i in 0..self.len.load(Ordering::Acquire) {
+            if let Some(frame) = self.frame(base + i * PAGE_SIZE) {
+                if frame.refcount() != 0 {
+                    usage.pages[frame.owner() as usize] += 1;
+                }
+            }
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::vec::Vec;
+
+    fn database(span: &Range) -> (FrameDatabase, Vec<Frame>) {
+        let mut frames: Vec<Frame> = (0..span.len() / PAGE_SIZE).map(|_| Frame::default()).collect();
+        let db = FrameDatabase::empty();
+        unsafe { db.init(span, frames.as_mut_ptr()) };
+        (db, frames)
+    }
+
+    #[test]
+    fn get_put() {
+        let span = Range::new(0x8000_0000, 0x8010_0000);
+        let (db, _frames) = database(&span);
+        let rg = Range::new(0x8000_2000, 0x8000_4000);
+        db.claim(&rg, Owner::User);
+        db.get(0x8000_2000);
+        assert_eq!(db.frame(0x8000_2fff).unwrap().refcount(), 2);
+        assert!(!db.put(0x8000_2000));
+        assert!(db.put(0x8000_2000));
+        assert!(db.put(0x8000_3000));
+        // Frames we don't manage are ignored.
+        assert!(db.frame(0x1000_0000).is_none());
+        assert!(db.frame(span.end).is_none());
+        assert!(!db.put(0x1000_0000));
+        db.get(0x1000_0000);
+    }
+
+    #[test]
+    fn pinned_frames_are_never_freed() {
+        let span = Range::new(0x8000_0000, 0x8010_0000);
+        let (db, _frames) = database(&span);
+        db.pin(&Range::new(0x8000_0000, 0x8000_1000), Owner::Kernel);
+        for _ in 0..4 {
+            db.get(0x8000_0000);
+            assert!(!db.put(0x8000_0000));
+            assert!(!db.put(0x8000_0000));
+        }
+        assert_eq!(db.frame(0x8000_0000).unwrap().refcount(), 1);
+        assert_eq!(db.frame(0x8000_0000).unwrap().flags(), FRAME_PINNED);
+    }
+
+    #[test]
+    #[should_panic]
+    fn put_on_free_frame() {
+        let span = Range::new(0x8000_0000, 0x8010_0000);
+        let (db, _frames) = database(&span);
+        db.put(0x8000_0000);
+    }
+
+    #[test]
+    fn usage_by_owner() {
+        let span = Range::new(0x8000_0000, 0x8010_0000);
+        let (db, _frames) = database(&span);
+        db.pin(&Range::new(0x8000_0000, 0x8000_4000), Owner::Kernel);
+        db.claim(&Range::new(0x8000_4000, 0x8000_5000), Owner::PageTable);
+        db.claim(&Range::new(0x8000_8000, 0x8001_0000), Owner::Kernel);
+        db.set_owner(&Range::new(0x8000_8000, 0x8001_0000), Owner::Slab);
+        db.claim(&Range::new(0x8001_0000, 0x8001_2000), Owner::PageCache);
+        db.release(&Range::new(0x8001_1000, 0x8001_2000));
+        let usage = db.usage();
+        assert_eq!(usage.get(Owner::Kernel), 4);
+        assert_eq!(usage.get(Owner::PageTable), 1);
+        assert_eq!(usage.get(Owner::Slab), 8);
+        assert_eq!(usage.get(Owner::PageCache), 1);
+        assert_eq!(usage.get(Owner::User), 0);
+        assert_eq!(usage.get(Owner::Free), 0);
+    }
+}
@@ -0,0 +1,17 @@
+/// Hardware thread helpers.
+
+/// The most harts we support. Each gets its own trap context and stack.
+pub const MAX_HARTS: usize = 8;
+
+/// For start.S, which parks harts past the limit.
+#[export_name = "MAX_HARTS"]
+static MAX_HARTS_FOR_ASM: usize = MAX_HARTS;
+
+/// Returns the id of the current hart. start.S stashes it in tp, which the
+/// compiler never allocates.
+pub fn id() -> usize {
+    let id: usize;
+    unsafe { asm!("mv $0, tp" : "=r"(id)); }
+    assert!(id < MAX_HARTS);
+    id
+}
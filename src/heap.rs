@@ -1,5 +1,8 @@
 /// Constants and utility functions used to set up the heap.
 use super::constants::{MB, PAGE_SIZE};
+use crate::debug;
+use crate::logger;
+use crate::phys::{self, PhysicalRange};
 extern "C" {
     static mut __kernel_end: u8;
 }
@@ -30,3 +33,45 @@ pub fn get_base() -> usize {
 pub fn get_size() -> usize {
     16 * MB
 }
+
+/// Smallest amount of memory the heap grows by at once. The allocator only
+/// tracks a handful of arenas, so don't grow by single pages.
+const GROW_SIZE: usize = 4 * MB;
+
+/// Called by the global allocator when it runs out of memory. The allocator
+/// is locked, so this must not allocate from the heap.
+pub fn grow(min_size: usize) -> Option<(*mut u8, usize)> {
+    let size = core::cmp::max(round_next(min_size, PAGE_SIZE), GROW_SIZE);
+    let rg = phys::alloc(size)?;
+    let (start, end) = unsafe { rg.bits() };
+    Some((start as *mut u8, end - start))
+}
+
+/// Give an arena handed out by `grow` back to the physical allocator.
+pub fn shrink(base: *mut u8, len: usize) {
+    let start = base as usize;
+    drop(unsafe { PhysicalRange::remake(start, start + len) });
+}
+
+// Frames between return_address and whoever called into the allocator:
+// trace_caller, the GlobalAllocator entry point which calls it directly, and
+// the __rg_ and __rust_ shims. The same for alloc and realloc, whichever path
+// the allocation takes.
+const TRACE_SKIP_FRAMES: usize = 4;
+
+fn trace_caller() -> usize {
+    debug::return_address(TRACE_SKIP_FRAMES)
+}
+
+/// Start recording live heap allocations and who made them.
+#[cfg(not(test))]
+pub fn enable_tracing() {
+    crate::GLOBAL.enable_tracing(trace_caller);
+}
+
+/// Log the live traced allocations grouped by call site. Pipe the log
+/// through scripts/symbolize.py to resolve the call sites.
+#[cfg(not(test))]
+pub fn dump_leaks() {
+    let _ = crate::GLOBAL.dump_leaks(&mut logger::Console::new());
+}
@@ -11,6 +11,7 @@ extern crate alloc;
 extern crate simplealloc;
 extern crate simplespin as mutex;
 use alloc::vec;
+use alloc::vec::Vec;
 
 #[cfg(not(test))]
 #[global_allocator]
@@ -22,20 +23,27 @@ mod uart;
 use uart as logger;
 
 
+mod buddy;
 mod constants;
 mod debug;
 mod device_tree;
+mod frames;
+mod hart;
 mod heap;
 mod interrupts;
+mod magazine;
 mod math;
 mod mmio;
 mod mmu;
 mod phys;
+mod plic;
 mod runtime;
 mod range;
+mod timer;
 mod trap;
 use core::slice;
 use device_tree::DeviceTree;
+use range::Range;
 
 /// Rust entry point called by the init hardware thread after we enter
 /// supervisor mode.
@@ -44,6 +52,7 @@ use device_tree::DeviceTree;
 /// The kernel will use the device tree to configure itself.
 #[no_mangle]
 pub extern "C" fn rmain(_hartid: usize, device_tree_addr: usize) {
+    trap::init();
     let mut device_tree = DeviceTree::empty();
     unsafe {
         device_tree = DeviceTree::from_address(device_tree_addr).expect("Invalid device tree");
@@ -53,12 +62,41 @@ pub extern "C" fn rmain(_hartid: usize, device_tree_addr: usize) {
         .find_regs("uart")
         .expect("uart not found in device tree");
     let uart_mem = unsafe { slice::from_raw_parts_mut(uart_base as *mut u8, uart_size) };
-    logger::LOGGER.lock().init(uart_mem);
+    logger::init(uart_mem);
+    timer::init(&device_tree);
+    plic::init(&device_tree);
 
     let heap_base = heap::get_base() as *mut u8;
     let heap_size = heap::get_size();
     #[cfg(not(test))]
     GLOBAL.init(heap_base, heap_size);
+
+    // FIXME: Only the first memory node is used.
+    let memory: Vec<Range> = device_tree
+        .find_property("memory", "reg")
+        .expect("memory not found in device tree")
+        .regs()
+        .map(|(start, size)| Range::new(start, start + size))
+        .collect();
+    let kernel_start = unsafe { &math::__kernel_start as *const u8 as usize };
+    let kernel_end = unsafe { &math::__kernel_end as *const u8 as usize };
+    let mut reserved = vec![
+        Range::new(kernel_start, kernel_end),
+        Range::new(device_tree_addr, device_tree_addr + device_tree.size()),
+        Range::new(heap_base as usize, heap_base as usize + heap_size),
+    ];
+    // Firmware keeps the memory below the kernel.
+    #[cfg(feature = "sbi")]
+    reserved.push(Range::new(memory[0].start, kernel_start));
+    if let Some((initrd_start, initrd_end)) = device_tree.initrd() {
+        reserved.push(Range::new(initrd_start, initrd_end));
+    }
+    phys::init(&memory, &reserved);
+    // Now that there's a physical allocator, the heap can grow past its
+    // initial arena.
+    #[cfg(not(test))]
+    GLOBAL.register_grow(heap::grow, heap::shrink);
+
     device_tree.dump();
     let v = vec![1, 2, 3];
 
@@ -1,6 +1,7 @@
 /// Macros for logging.
 /// These are independent of the logger implementation, although we expect
-/// logger implementations to be writers wrapped in locks.
+/// the logger to provide a `Console` writer which buffers output and writes
+/// it out when dropped, without blocking.
 
 #[macro_export]
 macro_rules! log {
@@ -8,14 +9,14 @@ macro_rules! log {
         {
             use core::fmt::Write;
             use crate::logger;
-            writeln!(&mut *logger::LOGGER.lock(), "{}", format_args!($fmt, $($arg)*));
+            writeln!(logger::Console::new(), "{}", format_args!($fmt, $($arg)*));
         }
     };
     ($fmt:tt) => {
         {
             use core::fmt::Write;
             use crate::logger;
-            writeln!(&mut *logger::LOGGER.lock(), "{}", format_args!($fmt));
+            writeln!(logger::Console::new(), "{}", format_args!($fmt));
         }
     };
 }
@@ -26,6 +27,6 @@ macro_rules! hexdump {
         use crate::debug;
         use crate::logger;
         use core::fmt::Write;
-        debug::hexdump(&mut *logger::LOGGER.lock(), $bytes);
+        debug::hexdump(&mut logger::Console::new(), $bytes);
     }};
 }
@@ -0,0 +1,188 @@
+/// Per-hart caches of free pages.
+/// Each hart keeps a small magazine of free pages so that most single page
+/// allocations and frees don't need the global allocator lock. Magazines are
+/// refilled and drained in batches.
+use core::ops::DerefMut;
+
+/// Pages a magazine can hold.
+pub const MAGAZINE_SIZE: usize = 64;
+/// Pages moved to or from the global allocator at once.
+pub const BATCH: usize = MAGAZINE_SIZE / 2;
+
+/// Where magazines get pages from and return them to.
+pub trait PageSource {
+    /// Fill `pages` with free page addresses. Returns how many were filled.
+    fn alloc_pages(&mut self, pages: &mut [usize]) -> usize;
+    /// Take back free pages.
+    fn free_pages(&mut self, pages: &[usize]);
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MagazineStats {
+    pub allocs: usize,
+    pub hits: usize,
+    pub frees: usize,
+    pub refills: usize,
+    pub drains: usize,
+}
+
+impl MagazineStats {
+    /// Percentage of allocations served without the global allocator.
+    pub fn hit_rate(&self) -> usize {
+        if self.allocs == 0 {
+            return 0;
+        }
+        self.hits * 100 / self.allocs
+    }
+
+    /// Times this hart took the global allocator lock.
+    pub fn global_locks(&self) -> usize {
+        self.refills + self.drains
+    }
+}
+
+pub struct Magazine {
+    pages: [usize; MAGAZINE_SIZE],
+    count: usize,
+    stats: MagazineStats,
+}
+
+impl Magazine {
+    pub const fn empty() -> Self {
+        Self {
+            pages: [0; MAGAZINE_SIZE],
+            count: 0,
+            stats: MagazineStats { allocs: 0, hits: 0, frees: 0, refills: 0, drains: 0 },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn stats(&self) -> MagazineStats {
+        self.stats
+    }
+
+    /// Take a page, refilling from `source` if the magazine is empty.
+    /// `source` is only called on a miss, so it can take the global lock.
+    pub fn alloc<G>(&mut self, source: impl FnOnce() -> G) -> Option<usize>
+    where
+        G: DerefMut,
+        G::Target: PageSource,
+    {
+        self.stats.allocs += 1;
+        if self.count != 0 {
+            self.stats.hits += 1;
+        } else {
+            self.stats.refills += 1;
+            self.count = source().alloc_pages(&mut self.pages[..BATCH]);
+        }
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        Some(self.pages[self.count])
+    }
+
+    /// Return a page, draining a batch to `source` if the magazine is full.
+    pub fn free<G>(&mut self, page: usize, source: impl FnOnce() -> G)
+    where
+        G: DerefMut,
+        G::Target: PageSource,
+    {
+        self.stats.frees += 1;
+        if self.count == MAGAZINE_SIZE {
+            self.stats.drains += 1;
+            self.count -= BATCH;
+            source().free_pages(&self.pages[self.count..]);
+        }
+        self.pages[self.count] = page;
+        self.count += 1;
+    }
+
+    /// Return every cached page to `source`.
+    pub fn drain(&mut self, source: &mut dyn PageSource) {
+        source.free_pages(&self.pages[..self.count]);
+        self.count = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::constants::PAGE_SIZE;
+    use std::vec::Vec;
+
+    struct FakeSource {
+        free: Vec<usize>,
+        calls: usize,
+    }
+
+    impl PageSource for FakeSource {
+        fn alloc_pages(&mut self, pages: &mut [usize]) -> usize {
+            self.calls += 1;
+            let n = core::cmp::min(pages.len(), self.free.len());
+            for page in pages[..n].iter_mut() {
+                *page = self.free.pop().unwrap();
+            }
+            n
+        }
+        fn free_pages(&mut self, pages: &[usize]) {
+            self.calls += 1;
+            self.free.extend_from_slice(pages);
+        }
+    }
+
+    fn source(pages: usize) -> FakeSource {
+        FakeSource { free: (0..pages).map(|i| 0x8000_0000 + i * PAGE_SIZE).collect(), calls: 0 }
+    }
+
+    #[test]
+    fn batches_hit_the_source() {
+        let mut src = source(4 * MAGAZINE_SIZE);
+        let mut mag = Magazine::empty();
+        let mut held = Vec::new();
+        for _ in 0..BATCH {
+            held.push(mag.alloc(|| &mut src).unwrap());
+        }
+        // One refill served the whole batch.
+        assert_eq!(src.calls, 1);
+        assert_eq!(mag.stats().hits, BATCH - 1);
+        held.push(mag.alloc(|| &mut src).unwrap());
+        assert_eq!(src.calls, 2);
+
+        for page in held.drain(..) {
+            mag.free(page, || &mut src);
+        }
+        assert_eq!(mag.len(), MAGAZINE_SIZE);
+        assert_eq!(src.calls, 2);
+
+        // One more overflows and forces a drain.
+        mag.free(0x9000_0000, || &mut src);
+        assert_eq!(mag.stats().drains, 1);
+        assert_eq!(mag.len(), MAGAZINE_SIZE - BATCH + 1);
+        assert_eq!(mag.stats().global_locks(), 3);
+        assert_eq!(src.calls, 3);
+
+        let cached = mag.len();
+        let before = src.free.len();
+        mag.drain(&mut src);
+        assert_eq!(mag.len(), 0);
+        assert_eq!(src.free.len(), before + cached);
+    }
+
+    #[test]
+    fn empty_source() {
+        let mut src = source(3);
+        let mut mag = Magazine::empty();
+        assert!(mag.alloc(|| &mut src).is_some());
+        assert!(mag.alloc(|| &mut src).is_some());
+        assert!(mag.alloc(|| &mut src).is_some());
+        assert_eq!(mag.alloc(|| &mut src), None);
+        assert_eq!(mag.stats().allocs, 4);
+        assert_eq!(mag.stats().hits, 2);
+        assert_eq!(mag.stats().hit_rate(), 50);
+    }
+}
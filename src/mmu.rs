@@ -30,19 +30,23 @@ impl PageTableEntry {
         }
     }
     fn from_paddr(phys: PAddr, access: MemoryAccess) -> Self {
-        assert!((phys & 0xfff) != 0);
-        Self(phys | access_to_arch(access) | PTE_V)
+        assert!((phys & 0xfff) == 0);
+        Self(((phys >> PAGE_OFFSET) << PTE_PPN_SHIFT) | access_to_arch(access) | PTE_V)
     }
 
-    fn to_paddr(&self) -> &PAddr {
-        &(self.0 & PTE_PPN_MASK)
+    // The PPN starts at bit 10, not 12.
+    fn to_paddr(&self) -> PAddr {
+        ((self.0 & PTE_PPN_MASK) >> PTE_PPN_SHIFT) << PAGE_OFFSET
     }
 
     // We deliberately DO NOT impl drop here, because there maybe shared memory
     // mappings, e.g. the kernel itself, which we do not want to unmap.
     fn release(&mut self) {
-
-
+        // The frame database knows whether anyone else still maps the frame,
+        // and ignores MMIO and pinned kernel frames.
+        if self.is_valid() {
+            phys::put(self.to_paddr());
+        }
         self.0 = 0;
     }
 }
@@ -57,7 +61,8 @@ const SATP_MODE_SV39: usize = 8 << 60;
 const SATP_MODE_SV48: usize = 9 << 60;
 
 // Section  4.3.1, fig 4.15
-const PTE_PPN_MASK: usize = 0x3ffffffffff000;
+const PTE_PPN_SHIFT: usize = 10;
+const PTE_PPN_MASK: usize = 0x3f_ffff_ffff_fc00;
 const PTE_RSW_COW: usize = 1 << 8;
 const PTE_DIRTY: usize = 1 << 7;
 const PTE_ACCESSED: usize = 1 << 6;
@@ -106,7 +111,7 @@ impl AddressSpace {
     }
     fn pte_to_page_table_level(pte: PageTableEntry) -> *mut PageTableLevel {
         assert!((pte & PTE_V) == PTE_V);
-        phys_to_virt(pte & PTE_PPN_MASK) as *mut PageTableLevel
+        phys_to_virt(pte.to_paddr()) as *mut PageTableLevel
     }
 
     fn identity_map_kernel() { }
@@ -1,41 +1,367 @@
+use crate::buddy::{self, BuddyAllocator, MAX_ORDER};
+use crate::constants::{KB, PAGE_SIZE};
+use crate::frames::{Frame, FrameDatabase, FrameUsage, Owner, FRAME_DB};
+use crate::hart::{self, MAX_HARTS};
+use crate::magazine::{Magazine, MagazineStats, PageSource};
+use crate::math::{align_down_by, align_up_by};
 use crate::range::{RangeSet, Range};
-use mutex::Mutex;
+use mutex::IrqMutex;
 
 pub struct PhysicalRange {
     rg: Range,
 }
 
 pub struct PhysicalRangeAllocator {
-    rs: RangeSet,
+    buddy: BuddyAllocator,
+    // Bytes of usable RAM handed to the allocator at init.
+    total: usize,
 }
 
-static PHYS_ALLOC: Mutex<PhysicalRangeAllocator> = Mutex::new(PhysicalRangeAllocator { rs: RangeSet::empty(), });
+static PHYS_ALLOC: IrqMutex<PhysicalRangeAllocator> = IrqMutex::new(PhysicalRangeAllocator::empty());
+
+// Single page allocations and frees go through the current hart's magazine
+// and only take the PHYS_ALLOC lock to refill or drain it.
+const EMPTY_MAGAZINE: IrqMutex<Magazine> = IrqMutex::new(Magazine::empty());
+static MAGAZINES: [IrqMutex<Magazine>; MAX_HARTS] = [EMPTY_MAGAZINE; MAX_HARTS];
 
 impl PhysicalRangeAllocator {
-    pub fn alloc(&mut self, sz: usize) -> Option<PhysicalRange> {
-        if let Some(rg) = self.rs.find(sz) {
-            return Some(PhysicalRange { rg });
+    const fn empty() -> Self {
+        Self { buddy: BuddyAllocator::empty(), total: 0 }
+    }
+
+    /// Hand the usable memory over to the buddy allocator. The buddy
+    /// allocator and the frame database cover all of `memory`, and their
+    /// metadata is carved out of the usable memory first. Reserved ranges are
+    /// pinned in the frame database.
+    fn init(&mut self, memory: &[Range], mut usable: RangeSet, reserved: &[Range]) {
+        if usable.iter().next().is_none() {
+            panic!("No usable physical memory");
+        }
+        let first = memory.iter().map(|rg| rg.start).min().unwrap();
+        let last = memory.iter().map(|rg| rg.end).max().unwrap();
+        let span = Range::new(align_down_by(first, PAGE_SIZE), align_up_by(last, PAGE_SIZE));
+        let metadata_size = align_up_by(BuddyAllocator::metadata_size(&span), PAGE_SIZE);
+        let metadata = usable
+            .find_constrained(metadata_size, PAGE_SIZE, usize::MAX)
+            .expect("No room for the frame allocator metadata");
+        unsafe { self.buddy.init(&span, metadata.start as *mut u8) };
+        let frames_size = align_up_by(FrameDatabase::size_for(&span), PAGE_SIZE);
+        let frames = usable
+            .find_constrained(frames_size, PAGE_SIZE, usize::MAX)
+            .expect("No room for the frame database");
+        unsafe { FRAME_DB.init(&span, frames.start as *mut Frame) };
+        FRAME_DB.pin(&metadata, Owner::Kernel);
+        FRAME_DB.pin(&frames, Owner::Kernel);
+        for rsv in reserved {
+            // Like usable_memory, partial pages count as reserved.
+            let rsv = Range::new(align_down_by(rsv.start, PAGE_SIZE), align_up_by(rsv.end, PAGE_SIZE));
+            FRAME_DB.pin(&rsv, Owner::Kernel);
+        }
+        for rg in usable.iter() {
+            unsafe { self.buddy.free_range(rg) };
+            self.total += rg.len();
         }
-        None
+    }
+
+    /// Allocate at least `sz` bytes. Sizes are rounded up to a power of two
+    /// pages.
+    pub fn alloc(&mut self, sz: usize) -> Option<PhysicalRange> {
+        let order = buddy::order_for(sz);
+        let start = self.buddy.alloc(order)?;
+        Some(self.make_range(start, order))
     }
     // Don't need free, because it's implemented as drop.
+
+    /// Allocate `size` bytes starting on an `align` boundary which end at or
+    /// below `max_addr`. Use this for page tables, huge pages and DMA buffers
+    /// for devices which can't address all of RAM.
+    pub fn alloc_constrained(&mut self, size: usize, align: usize, max_addr: usize) -> Option<PhysicalRange> {
+        let order = buddy::order_for(size);
+        let start = self.buddy.alloc_constrained(order, align, max_addr)?;
+        Some(self.make_range(start, order))
+    }
+
+    fn make_range(&mut self, start: usize, order: usize) -> PhysicalRange {
+        let rg = Range::new(start, start + buddy::order_size(order));
+        FRAME_DB.claim(&rg, Owner::Kernel);
+        PhysicalRange { rg }
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Bytes free in the global allocator, not counting the magazines.
+    pub fn free(&self) -> usize {
+        self.buddy.free_bytes()
+    }
+
+    /// Log the free blocks of each order.
+    pub fn dump(&self) {
+        log!("Physical allocator {{");
+        for order in 0..MAX_ORDER {
+            let n = self.buddy.free_blocks(order);
+            if n != 0 {
+                log!("  {} KiB blocks: {}", buddy::order_size(order) / KB, n);
+            }
+        }
+        log!("}} total {} KiB free {} KiB", self.total / KB, self.free() / KB);
+    }
+}
+
+impl PageSource for PhysicalRangeAllocator {
+    fn alloc_pages(&mut self, pages: &mut [usize]) -> usize {
+        for (i, page) in pages.iter_mut().enumerate() {
+            match self.buddy.alloc(0) {
+                Some(addr) => *page = addr,
+                None => return i,
+            }
+        }
+        pages.len()
+    }
+
+    fn free_pages(&mut self, pages: &[usize]) {
+        for page in pages {
+            unsafe { self.buddy.free(*page, 0) };
+        }
+    }
+}
+
+/// The page-aligned parts of the `memory` regions which don't overlap any of
+/// the `reserved` ranges.
+fn usable_memory(memory: &[Range], reserved: &[Range]) -> RangeSet {
+    let mut usable = RangeSet::empty();
+    for region in memory {
+        let start = align_up_by(region.start, PAGE_SIZE);
+        let end = align_down_by(region.end, PAGE_SIZE);
+        if start < end {
+            usable.insert(Range::new(start, end));
+        }
+    }
+    for rsv in reserved {
+        // Reserved ranges lose any partial pages on either side.
+        let start = align_down_by(rsv.start, PAGE_SIZE);
+        let end = align_up_by(rsv.end, PAGE_SIZE);
+        usable.subtract(&Range::new(start, end));
+    }
+    usable
+}
+
+/// Seed the physical allocator with the memory regions from the device tree.
+/// * `memory` - The RAM regions described by the device tree.
+/// * `reserved` - Regions already in use, such as the kernel image, the device
+/// tree blob, the initrd and the early heap. They may overlap each other.
+pub fn init(memory: &[Range], reserved: &[Range]) {
+    let usable = usable_memory(memory, reserved);
+    log!("Physical memory map {{");
+    for rg in usable.iter() {
+        log!("  {:#x}-{:#x} ({} KiB)", rg.start, rg.end, rg.len() / KB);
+    }
+    log!("}}");
+    let mut pa = PHYS_ALLOC.lock();
+    pa.init(memory, usable, reserved);
+    pa.dump();
+    FRAME_DB.usage().dump();
+}
+
+pub fn alloc(sz: usize) -> Option<PhysicalRange> {
+    if sz <= PAGE_SIZE {
+        return alloc_page();
+    }
+    PHYS_ALLOC.lock().alloc(sz)
 }
 
+/// Allocate a single page from the current hart's magazine.
+pub fn alloc_page() -> Option<PhysicalRange> {
+    let page = MAGAZINES[hart::id()].lock().alloc(|| PHYS_ALLOC.lock())?;
+    let rg = Range::new(page, page + PAGE_SIZE);
+    FRAME_DB.claim(&rg, Owner::Kernel);
+    Some(PhysicalRange { rg })
+}
+
+pub fn alloc_constrained(size: usize, align: usize, max_addr: usize) -> Option<PhysicalRange> {
+    PHYS_ALLOC.lock().alloc_constrained(size, align, max_addr)
+}
+
+/// Total bytes of RAM managed by the physical allocator.
+pub fn total() -> usize {
+    PHYS_ALLOC.lock().total()
+}
+
+/// Bytes of RAM which are currently free, including pages cached in the
+/// per-hart magazines.
+pub fn free() -> usize {
+    let cached: usize = MAGAZINES.iter().map(|m| m.lock().len()).sum();
+    PHYS_ALLOC.lock().free() + cached * PAGE_SIZE
+}
+
+/// Magazine statistics for each hart.
+pub fn cache_stats() -> [MagazineStats; MAX_HARTS] {
+    let mut stats = [MagazineStats::default(); MAX_HARTS];
+    for (stat, mag) in stats.iter_mut().zip(MAGAZINES.iter()) {
+        *stat = mag.lock().stats();
+    }
+    stats
+}
+
+/// Log the hit rate and global lock count of each hart's magazine.
+pub fn dump_cache_stats() {
+    for (hart, stat) in cache_stats().iter().enumerate() {
+        if stat.allocs == 0 && stat.frees == 0 {
+            continue;
+        }
+        log!(
+            "hart {}: {} allocs {}% hits, {} frees, {} refills, {} drains",
+            hart,
+            stat.allocs,
+            stat.hit_rate(),
+            stat.frees,
+            stat.refills,
+            stat.drains
+        );
+    }
+}
+
+/// Take another reference to the frame containing `addr`, e.g. when sharing
+/// it between address spaces.
+pub fn get(addr: usize) {
+    FRAME_DB.get(addr);
+}
+
+/// Drop a reference to the frame containing `addr`, freeing it if that was
+/// the last one. Frames outside of RAM and pinned frames are left alone.
+pub fn put(addr: usize) {
+    if FRAME_DB.put(addr) {
+        let page = align_down_by(addr, PAGE_SIZE);
+        free_frames(&Range::new(page, page + PAGE_SIZE));
+    }
+}
+
+/// Pages of RAM in use by each owner.
+pub fn usage() -> FrameUsage {
+    FRAME_DB.usage()
+}
 
 impl PhysicalRange {
     // Used by mmu which needs to make and unmake ranges to put them in the
-    // page tables.
-    unsafe fn remake(start: usize, end: usize) -> Self {
+    // page tables, and by the heap to grow and shrink.
+    pub unsafe fn remake(start: usize, end: usize) -> Self {
         Self { rg: Range::new(start, end) }
     }
-    unsafe fn bits(self) -> (usize, usize) {
-        (self.rg.start, self.rg.end)
+    // Hands the frames' references over to the caller, who must give them
+    // back with `put` or `remake`.
+    pub unsafe fn bits(self) -> (usize, usize) {
+        let bits = (self.rg.start, self.rg.end);
+        core::mem::forget(self);
+        bits
+    }
+
+    pub fn range(&self) -> &Range {
+        &self.rg
+    }
+
+    /// Hand the frames to another subsystem in the frame database.
+    pub fn set_owner(&self, owner: Owner) {
+        FRAME_DB.set_owner(&self.rg, owner);
     }
 }
 
+/// Give frames whose last reference is gone back to the allocator.
+fn free_frames(rg: &Range) {
+    FRAME_DB.release(rg);
+    if rg.len() == PAGE_SIZE {
+        MAGAZINES[hart::id()].lock().free(rg.start, || PHYS_ALLOC.lock());
+        return;
+    }
+    unsafe { PHYS_ALLOC.lock().buddy.free_range(rg) };
+}
+
 impl Drop for PhysicalRange {
     fn drop(&mut self) {
-        // Hmm how do I just consume the value?
-        PHYS_ALLOC.lock().rs.insert(self.rg.clone());
+        // Drop the range's reference to each frame. Frames somebody took
+        // another reference to with `get` stay allocated until their last
+        // `put`, the rest go back in runs.
+        let mut run = self.rg.start;
+        for addr in (self.rg.start..self.rg.end).step_by(PAGE_SIZE) {
+            let last = FRAME_DB.frame(addr).is_none() || FRAME_DB.put(addr);
+            if !last {
+                if run < addr {
+                    free_frames(&Range::new(run, addr));
+                }
+                run = addr + PAGE_SIZE;
+            }
+        }
+        if run < self.rg.end {
+            free_frames(&Range::new(run, self.rg.end));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use crate::constants::MB;
+    use std::vec::Vec;
+
+    #[test]
+    fn usable_memory_carves_reserved() {
+        let reserved = [
+            // Early heap, directly after the kernel.
+            Range::new(0x8020_0000, 0x8120_0000),
+            // Kernel image.
+            Range::new(0x8000_0000, 0x8012_3456),
+            // Device tree blob, not page aligned.
+            Range::new(0x87e0_0800, 0x87e0_1800),
+        ];
+        let usable = usable_memory(&[Range::new(0x8000_0000, 0x8800_0000)], &reserved);
+        let free: Vec<Range> = usable.iter().cloned().collect();
+        assert_eq!(
+            free,
+            [
+                Range::new(0x8012_4000, 0x8020_0000),
+                Range::new(0x8120_0000, 0x87e0_0000),
+                Range::new(0x87e0_2000, 0x8800_0000),
+            ]
+        );
+    }
+
+    #[test]
+    fn usable_memory_unaligned_and_fully_reserved() {
+        let memory = [Range::new(0x800, 0x3800), Range::new(0x10000, 0x20000)];
+        let reserved = [Range::new(0xf000, 0x21000), Range::new(0x1800, 0x1900)];
+        let usable = usable_memory(&memory, &reserved);
+        let free: Vec<Range> = usable.iter().cloned().collect();
+        assert_eq!(free, [Range::new(0x2000, 0x3000)]);
+    }
+
+    #[test]
+    fn init_counts_usable_memory() {
+        // Host memory standing in for RAM. It's leaked since FRAME_DB keeps
+        // pointing at it.
+        let backing = std::vec![0u8; 5 * MB].leak();
+        let start = align_up_by(backing.as_ptr() as usize, MB);
+        let memory = [Range::new(start, start + 4 * MB)];
+        let reserved = [
+            // Kernel image, not page aligned.
+            Range::new(start, start + 0x1234),
+            Range::new(start + 2 * MB, start + 2 * MB + 0x800),
+        ];
+        let usable = usable_memory(&memory, &reserved);
+        let usable_len: usize = usable.iter().map(Range::len).sum();
+        assert_eq!(usable_len, 4 * MB - 3 * PAGE_SIZE);
+        let mut pa = PhysicalRangeAllocator::empty();
+        pa.init(&memory, usable, &reserved);
+        let metadata = align_up_by(BuddyAllocator::metadata_size(&memory[0]), PAGE_SIZE)
+            + align_up_by(FrameDatabase::size_for(&memory[0]), PAGE_SIZE);
+        assert_eq!(pa.total(), usable_len - metadata);
+        assert_eq!(pa.free(), pa.total());
+        // The reserved ranges are inside the frame database too.
+        assert_eq!(FRAME_DB.usage().get(Owner::Kernel), 3 + metadata / PAGE_SIZE);
+        let rg = pa.alloc(2 * PAGE_SIZE).unwrap();
+        assert_eq!(pa.free(), pa.total() - 2 * PAGE_SIZE);
+        assert_eq!(FRAME_DB.usage().get(Owner::Kernel), 5 + metadata / PAGE_SIZE);
+        // Dropping would free to PHYS_ALLOC, not pa.
+        core::mem::forget(rg);
     }
 }
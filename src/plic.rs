@@ -0,0 +1,100 @@
+/// The platform-level interrupt controller, which routes device interrupts to
+/// harts as supervisor external interrupts.
+/// Drivers register a handler for their interrupt source, which enables it on
+/// the registering hart. The trap handler claims each pending source, runs
+/// its handler and completes it.
+use crate::log;
+use crate::device_tree::DeviceTree;
+use crate::hart;
+use crate::trap::{self, InterruptCause, Trap, TrapAction, TrapContext};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use mutex::SeqLock;
+
+/// Register offsets. Each source has a 32 bit priority, each context a
+/// bitmap of enabled sources and a page with its threshold and claim register.
+const PRIORITY: usize = 0;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0;
+const CLAIM: usize = 4;
+
+/// Sources past this can't be registered. qemu virt has 53.
+const MAX_SOURCES: usize = 64;
+
+/// Called with the source which interrupted.
+pub type IrqHandler = fn(source: usize);
+
+/// Zero until `init`.
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLERS: SeqLock<[Option<IrqHandler>; MAX_SOURCES]> = SeqLock::new([None; MAX_SOURCES]);
+
+/// The context for a hart's supervisor mode. Like qemu virt, this assumes
+/// each hart has a machine mode context and then a supervisor mode one.
+fn context(hart: usize) -> usize {
+    2 * hart + 1
+}
+
+fn reg(offset: usize) -> *mut u32 {
+    (BASE.load(Ordering::Acquire) + offset) as *mut u32
+}
+
+fn claim_reg(hart: usize) -> *mut u32 {
+    reg(CONTEXT + context(hart) * CONTEXT_STRIDE + CLAIM)
+}
+
+/// Find the PLIC and take external interrupts on this hart.
+pub fn init(device_tree: &DeviceTree) {
+    let base = match device_tree
+        .find("plic")
+        .or_else(|| device_tree.find("interrupt-controller"))
+    {
+        Some(base) => base,
+        None => {
+            log!("PLIC not found in device tree, no external interrupts");
+            return;
+        }
+    };
+    BASE.store(base, Ordering::Release);
+    let hart = hart::id();
+    // Every source with a non-zero priority gets through.
+    unsafe { reg(CONTEXT + context(hart) * CONTEXT_STRIDE + THRESHOLD).write_volatile(0) };
+    trap::register(
+        Trap::Interrupt(InterruptCause::SupervisorExternal),
+        external_interrupt,
+    );
+    log!("PLIC at {:x}", base);
+}
+
+/// Handle interrupts from `source` on this hart, returning the old handler.
+pub fn register(source: usize, handler: IrqHandler) -> Option<IrqHandler> {
+    // Source 0 means no interrupt.
+    assert!(source != 0 && source < MAX_SOURCES, "Bad PLIC source {}", source);
+    assert!(BASE.load(Ordering::Acquire) != 0, "PLIC not initialized");
+    let mut old = None;
+    HANDLERS.update(|handlers| old = handlers[source].replace(handler));
+    let enable = reg(ENABLE + context(hart::id()) * ENABLE_STRIDE + source / 32 * 4);
+    unsafe {
+        reg(PRIORITY + source * 4).write_volatile(1);
+        enable.write_volatile(enable.read_volatile() | 1 << (source % 32));
+    }
+    old
+}
+
+fn external_interrupt(_trapctx: &mut TrapContext) -> TrapAction {
+    let claim = claim_reg(hart::id());
+    loop {
+        let source = unsafe { claim.read_volatile() } as usize;
+        if source == 0 {
+            break;
+        }
+        match HANDLERS.read().get(source) {
+            Some(Some(handler)) => handler(source),
+            _ => log!("Unhandled PLIC source {}", source),
+        }
+        unsafe { claim.write_volatile(source as u32) };
+    }
+    TrapAction::Resume
+}
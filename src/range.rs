@@ -1,7 +1,7 @@
 use alloc::vec::Vec;
 use core::cmp;
-use core::cmp::Ordering;
 use crate::constants::PAGE_SIZE;
+use crate::math::{align_down_by, is_power_of_two};
 
 fn is_aligned_by(n: usize, alignment: usize) -> bool {
     (n & (alignment - 1)) == 0
@@ -55,17 +55,30 @@ impl Range {
         self.end -= len;
         r
     }
-}
 
-impl Ord for Range {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.len().cmp(&other.len())
+    /// Returns the start of the highest `len` byte sub-range which begins on
+    /// an `align` boundary and ends at or below `max_addr`, if there is one.
+    fn fit_constrained(&self, len: usize, align: usize, max_addr: usize) -> Option<usize> {
+        let top = cmp::min(self.end, max_addr);
+        let start = align_down_by(top.checked_sub(len)?, align);
+        if start < self.start {
+            return None;
+        }
+        Some(start)
     }
-}
 
-impl PartialOrd for Range {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    /// Cut `[start, start + len)` out of this range. Returns the head and tail
+    /// fragments which are left over, if they aren't empty, and the cut range.
+    fn carve(&self, start: usize, len: usize) -> (Option<Self>, Self, Option<Self>) {
+        let r = Range::new(start, start + len);
+        assert!(self.contains(&r));
+        let head = Range::new(self.start, r.start);
+        let tail = Range::new(r.end, self.end);
+        (
+            if head.len() != 0 { Some(head) } else { None },
+            r,
+            if tail.len() != 0 { Some(tail) } else { None },
+        )
     }
 }
 
@@ -76,6 +89,20 @@ impl PartialEq for Range {
 }
 
 
+/// Which free range `RangeSet::find_with` carves an allocation from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FitPolicy {
+    /// The first range in address order which is large enough, carved from
+    /// the top.
+    FirstFit,
+    /// The smallest range which is large enough, carved from the top. This
+    /// keeps large ranges intact.
+    BestFit,
+    /// The lowest addresses available, for DMA-friendly buffers.
+    LowestAddress,
+    /// The highest addresses available, which keeps low memory free.
+    HighestAddress,
+}
 
 #[derive(Debug, Default)]
 pub struct RangeSet {
@@ -86,41 +113,176 @@ impl RangeSet {
     pub const fn empty() -> Self {
         RangeSet { set: Vec::new() }
     }
+    /// Add a range to the set. It may overlap, contain, or bridge ranges
+    /// which are already in the set; everything it touches is coalesced.
     pub fn insert(&mut self, value: Range) {
-        let mut insert_index = 0;
-        for rg in self.set.iter_mut() {
-            assert!(!rg.contains(&value));
-            assert!(!rg.overlaps(&value));
-            if rg.can_merge(&value) {
-                rg.merge(value);
-                return;
-            }
-            if !rg.starts_before(&value) {
-                break;
+        if value.len() == 0 {
+            return;
+        }
+        let lo = self.lower_bound(value.start);
+        let mut hi = lo;
+        let mut merged = value;
+        while hi < self.set.len() && self.set[hi].start <= merged.end {
+            merged.start = cmp::min(merged.start, self.set[hi].start);
+            merged.end = cmp::max(merged.end, self.set[hi].end);
+            hi += 1;
+        }
+        self.set.splice(lo..hi, core::iter::once(merged));
+    }
+
+    /// Remove every address in `value` from the set. Ranges which straddle
+    /// either end of it are trimmed, or split in two.
+    pub fn subtract(&mut self, value: &Range) {
+        if value.len() == 0 {
+            return;
+        }
+        // The first range which ends after the start of the value.
+        let lo = self.lower_bound(value.start + 1);
+        let mut hi = lo;
+        while hi < self.set.len() && self.set[hi].start < value.end {
+            hi += 1;
+        }
+        if lo == hi {
+            return;
+        }
+        let head = Range::new(self.set[lo].start, value.start);
+        let tail = Range::new(value.end, self.set[hi - 1].end);
+        let keep = [head, tail];
+        let pieces = keep.iter().filter(|rg| rg.start < rg.end).cloned();
+        self.set.splice(lo..hi, pieces);
+    }
+
+    /// Add every range in `other` to this set.
+    pub fn union(&mut self, other: &Self) {
+        for rg in other.iter() {
+            self.insert(rg.clone());
+        }
+    }
+
+    /// Keep only the addresses which are also in `other`.
+    pub fn intersect(&mut self, other: &Self) {
+        let mut set = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < self.set.len() && j < other.set.len() {
+            let (a, b) = (&self.set[i], &other.set[j]);
+            let start = cmp::max(a.start, b.start);
+            let end = cmp::min(a.end, b.end);
+            if start < end {
+                set.push(Range::new(start, end));
+            }
+            if a.end < b.end {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        self.set = set;
+    }
+
+    /// Returns true if `addr` is inside one of the ranges in the set.
+    pub fn contains(&self, addr: usize) -> bool {
+        match self.set.get(self.lower_bound(addr.saturating_add(1))) {
+            Some(rg) => rg.start <= addr && addr < rg.end,
+            None => false,
+        }
+    }
+
+    /// Index of the first range which ends at or after `addr`. The set is
+    /// sorted and disjoint, so the ends are sorted too.
+    fn lower_bound(&self, addr: usize) -> usize {
+        let (mut lo, mut hi) = (0, self.set.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.set[mid].end < addr {
+                lo = mid + 1;
+            } else {
+                hi = mid;
             }
-            insert_index += 1;
         }
-        self.set.insert(insert_index, value);
+        lo
+    }
+
+    /// Iterate over the ranges in the set in address order.
+    pub fn iter(&self) -> core::slice::Iter<'_, Range> {
+        self.set.iter()
     }
 
     pub fn find(&mut self, sz: usize) -> Option<Range> {
-        let mut remove_at = None;
-        for (i, rg) in self.set.iter_mut().enumerate() {
-            if rg.len() == sz {
-                remove_at = Some(i);
+        self.find_with(sz, FitPolicy::FirstFit)
+    }
+
+    /// Remove a `sz` byte range from the set, choosing where it comes from
+    /// according to `policy`.
+    pub fn find_with(&mut self, sz: usize, policy: FitPolicy) -> Option<Range> {
+        assert!(is_aligned_by(sz, PAGE_SIZE));
+        let mut fits = self.set.iter().enumerate().filter(|(_, rg)| rg.len() >= sz);
+        let (i, rg) = match policy {
+            FitPolicy::FirstFit | FitPolicy::LowestAddress => fits.next(),
+            // min picks the lowest address on a tie.
+            FitPolicy::BestFit => fits.min_by_key(|(_, rg)| rg.len()),
+            FitPolicy::HighestAddress => fits.next_back(),
+        }?;
+        let start = match policy {
+            FitPolicy::LowestAddress => rg.start,
+            _ => rg.end - sz,
+        };
+        Some(self.take(i, start, sz))
+    }
+
+    /// Like `find`, but the returned range starts on an `align` boundary and
+    /// ends at or below `max_addr`. Whatever is left on either side of it
+    /// stays in the set.
+    pub fn find_constrained(&mut self, sz: usize, align: usize, max_addr: usize) -> Option<Range> {
+        assert!(is_aligned_by(sz, PAGE_SIZE));
+        assert!(is_power_of_two(align));
+        for i in 0..self.set.len() {
+            if self.set[i].start >= max_addr {
                 break;
             }
-            if rg.can_split(sz) {
-                let r = Some(rg.split(sz));
-                return r;
+            if let Some(start) = self.set[i].fit_constrained(sz, align, max_addr) {
+                return Some(self.take(i, start, sz));
             }
         }
-        if let Some(i) = remove_at {
-            let r =  Some(self.set.remove(i));
-            return r;
-        }
         None
     }
+
+    /// Cut `[start, start + sz)` out of the range at index `i`, leaving the
+    /// fragments on either side of it in the set.
+    fn take(&mut self, i: usize, start: usize, sz: usize) -> Range {
+        let (head, rg, tail) = self.set[i].carve(start, sz);
+        match (head, tail) {
+            (Some(head), Some(tail)) => {
+                self.set[i] = head;
+                self.set.insert(i + 1, tail);
+            }
+            (Some(head), None) => self.set[i] = head,
+            (None, Some(tail)) => self.set[i] = tail,
+            (None, None) => {
+                self.set.remove(i);
+            }
+        }
+        rg
+    }
+
+    /// Total bytes in the set.
+    pub fn total(&self) -> usize {
+        self.set.iter().map(Range::len).sum()
+    }
+
+    /// Length of the largest range in the set.
+    pub fn largest(&self) -> usize {
+        self.set.iter().map(Range::len).max().unwrap_or(0)
+    }
+
+    /// External fragmentation as a percentage: how much of the free memory
+    /// can't be handed out as one range. 0 means a single range.
+    pub fn fragmentation(&self) -> usize {
+        let total = self.total();
+        if total == 0 {
+            return 0;
+        }
+        100 - self.largest() * 100 / total
+    }
 }
 
 #[cfg(test)]
@@ -149,4 +311,337 @@ mod tests {
         assert_eq!(rs.find(0x1000), None);
         assert_eq!(rs.set.len(), 0);
     }
+
+    #[test]
+    fn fit_policies() {
+        let free = [
+            Range::new(0x0, 0x4000),
+            Range::new(0x8000, 0x9000),
+            Range::new(0x10000, 0x12000),
+        ];
+        let find = |sz, policy| {
+            let mut rs = RangeSet::empty();
+            for rg in free.iter() {
+                rs.insert(rg.clone());
+            }
+            rs.find_with(sz, policy)
+        };
+        assert_eq!(find(0x1000, FitPolicy::FirstFit), Some(Range::new(0x3000, 0x4000)));
+        assert_eq!(find(0x1000, FitPolicy::BestFit), Some(Range::new(0x8000, 0x9000)));
+        assert_eq!(find(0x2000, FitPolicy::BestFit), Some(Range::new(0x10000, 0x12000)));
+        assert_eq!(find(0x1000, FitPolicy::LowestAddress), Some(Range::new(0x0, 0x1000)));
+        assert_eq!(find(0x1000, FitPolicy::HighestAddress), Some(Range::new(0x11000, 0x12000)));
+        assert_eq!(find(0x3000, FitPolicy::HighestAddress), Some(Range::new(0x1000, 0x4000)));
+        assert_eq!(find(0x5000, FitPolicy::BestFit), None);
+    }
+
+    #[test]
+    fn fragmentation_metric() {
+        let mut rs = RangeSet::empty();
+        assert_eq!(rs.fragmentation(), 0);
+        rs.insert(Range::new(0x0, 0x4000));
+        assert_eq!(rs.fragmentation(), 0);
+        rs.insert(Range::new(0x8000, 0xc000));
+        assert_eq!(rs.fragmentation(), 50);
+        rs.insert(Range::new(0x10000, 0x18000));
+        assert_eq!(rs.largest(), 0x8000);
+        assert_eq!(rs.total(), 0x10000);
+        assert_eq!(rs.fragmentation(), 50);
+    }
+
+    /// One step of a replayable allocation trace.
+    #[derive(Clone, Copy, Debug)]
+    enum Event {
+        Alloc { id: usize, pages: usize },
+        Free { id: usize },
+    }
+
+    /// A pseudo random but reproducible mix of small and large allocations.
+    fn trace(seed: u64, len: usize) -> std::vec::Vec<Event> {
+        let mut state = seed;
+        let mut next = move || {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+        let mut live = std::vec::Vec::new();
+        let mut events = std::vec::Vec::new();
+        for id in 0..len {
+            // Keep roughly a hundred allocations live.
+            while live.len() > 100 || (!live.is_empty() && next() % 3 == 0) {
+                let victim = live.swap_remove(next() % live.len());
+                events.push(Event::Free { id: victim });
+            }
+            let pages = if next() % 8 == 0 { 16 + next() % 48 } else { 1 + next() % 4 };
+            events.push(Event::Alloc { id, pages });
+            live.push(id);
+        }
+        events
+    }
+
+    /// Replay a trace against 16 MiB of memory, which is enough that nothing
+    /// fails. Returns the number of failed allocations and the mean
+    /// fragmentation over the trace.
+    fn replay(events: &[Event], policy: FitPolicy) -> (usize, usize) {
+        use std::collections::BTreeMap;
+        const MEMORY: usize = 0x100_0000;
+        let mut rs = RangeSet::empty();
+        rs.insert(Range::new(0, MEMORY));
+        let mut live = BTreeMap::new();
+        let mut failures = 0;
+        let mut fragmentation = 0;
+        for event in events {
+            match *event {
+                Event::Alloc { id, pages } => match rs.find_with(pages * PAGE_SIZE, policy) {
+                    Some(rg) => {
+                        live.insert(id, rg);
+                    }
+                    None => failures += 1,
+                },
+                Event::Free { id } => {
+                    if let Some(rg) = live.remove(&id) {
+                        rs.insert(rg);
+                    }
+                }
+            }
+            let allocated: usize = live.values().map(Range::len).sum();
+            assert_eq!(rs.total() + allocated, MEMORY);
+            fragmentation += rs.fragmentation();
+        }
+        (failures, fragmentation / events.len())
+    }
+
+    /// Compare policies on the same trace. Every policy holds the same
+    /// allocations, and best fit should fragment no worse than first fit. Run
+    /// with `--nocapture` to see the numbers.
+    #[test]
+    fn fit_policy_trace() {
+        let events = trace(0x5eed_1234_abcd_ef01, 4000);
+        let mut results = std::collections::BTreeMap::new();
+        for policy in [
+            FitPolicy::FirstFit,
+            FitPolicy::BestFit,
+            FitPolicy::LowestAddress,
+            FitPolicy::HighestAddress,
+        ]
+        .iter()
+        {
+            let result = replay(&events, *policy);
+            // Replaying the same trace gives the same answer.
+            assert_eq!(result, replay(&events, *policy));
+            assert_eq!(result.0, 0, "{:?} failed allocations", policy);
+            std::println!(
+                "{:?}: {} failed allocations, {}% mean fragmentation",
+                policy, result.0, result.1
+            );
+            results.insert(std::format!("{:?}", policy), result);
+        }
+        assert!(results["BestFit"].1 <= results["FirstFit"].1);
+    }
+
+    #[test]
+    fn insert_coalesces() {
+        let mut rs = RangeSet::empty();
+        rs.insert(Range::new(0x1000, 0x2000));
+        rs.insert(Range::new(0x3000, 0x4000));
+        rs.insert(Range::new(0x6000, 0x7000));
+        // Bridges the first two.
+        rs.insert(Range::new(0x2000, 0x3000));
+        assert_eq!(rs.set, [Range::new(0x1000, 0x4000), Range::new(0x6000, 0x7000)]);
+        // Overlaps and contains.
+        rs.insert(Range::new(0x3800, 0x6800));
+        assert_eq!(rs.set, [Range::new(0x1000, 0x7000)]);
+        rs.insert(Range::new(0x2000, 0x3000));
+        rs.insert(Range::new(0x0, 0x8000));
+        assert_eq!(rs.set, [Range::new(0x0, 0x8000)]);
+    }
+
+    #[test]
+    fn subtract_and_contains() {
+        let mut rs = RangeSet::empty();
+        rs.insert(Range::new(0x0, 0x8000));
+        rs.insert(Range::new(0x9000, 0xa000));
+        rs.subtract(&Range::new(0x2000, 0x3000));
+        assert_eq!(
+            rs.set,
+            [Range::new(0x0, 0x2000), Range::new(0x3000, 0x8000), Range::new(0x9000, 0xa000)]
+        );
+        rs.subtract(&Range::new(0x1000, 0x9800));
+        assert_eq!(rs.set, [Range::new(0x0, 0x1000), Range::new(0x9800, 0xa000)]);
+        assert!(rs.contains(0x0));
+        assert!(rs.contains(0xfff));
+        assert!(!rs.contains(0x1000));
+        assert!(rs.contains(0x9800));
+        assert!(!rs.contains(0xa000));
+        assert!(!rs.contains(usize::MAX));
+    }
+
+    #[test]
+    fn find_constrained_splits_head_and_tail() {
+        let mut rs = RangeSet::empty();
+        rs.insert(Range::new(0x1000, 0x40_0000));
+        // Aligned to 2M, leaves a head and a tail.
+        assert_eq!(
+            rs.find_constrained(0x1000, 0x20_0000, 0x30_0000),
+            Some(Range::new(0x20_0000, 0x20_1000))
+        );
+        assert_eq!(rs.set, [Range::new(0x1000, 0x20_0000), Range::new(0x20_1000, 0x40_0000)]);
+        // Only a head.
+        assert_eq!(
+            rs.find_constrained(0x1000, 0x1000, usize::MAX),
+            Some(Range::new(0x1f_f000, 0x20_0000))
+        );
+        assert_eq!(rs.set, [Range::new(0x1000, 0x1f_f000), Range::new(0x20_1000, 0x40_0000)]);
+        // Only a tail.
+        assert_eq!(
+            rs.find_constrained(0x1000, 0x1000, 0x2000),
+            Some(Range::new(0x1000, 0x2000))
+        );
+        assert_eq!(rs.set, [Range::new(0x2000, 0x1f_f000), Range::new(0x20_1000, 0x40_0000)]);
+        // The whole range.
+        assert_eq!(
+            rs.find_constrained(0x1f_d000, 0x1000, 0x1f_f000),
+            Some(Range::new(0x2000, 0x1f_f000))
+        );
+        assert_eq!(rs.set, [Range::new(0x20_1000, 0x40_0000)]);
+        // Nothing low enough, and nothing aligned enough.
+        assert_eq!(rs.find_constrained(0x1000, 0x1000, 0x20_1000), None);
+        assert_eq!(rs.find_constrained(0x1000, 0x40_0000, usize::MAX), None);
+        assert_eq!(rs.set, [Range::new(0x20_1000, 0x40_0000)]);
+    }
+
+    #[test]
+    fn find_constrained_loses_nothing() {
+        use std::vec::Vec;
+        let original = [
+            Range::new(0x1000, 0x7f_f000),
+            Range::new(0x8000_0000, 0x8100_0000),
+            Range::new(0x1_0000_0000, 0x1_0040_0000),
+        ];
+        let mut rs = RangeSet::empty();
+        for rg in original.iter() {
+            rs.insert(rg.clone());
+        }
+        let mut allocated = Vec::new();
+        let aligns = [0x1000, 0x2000, 0x10000, 0x20_0000];
+        let sizes = [0x1000, 0x3000, 0x20_0000];
+        let limits = [usize::MAX, 0x1_0000_0000, 0x80_0000];
+        for i in 0..200 {
+            let (sz, align, max_addr) = (sizes[i % 3], aligns[i % 4], limits[i % 5 % 3]);
+            if let Some(rg) = rs.find_constrained(sz, align, max_addr) {
+                assert_eq!(rg.len(), sz);
+                assert!(is_aligned_by(rg.start, align));
+                assert!(rg.end <= max_addr);
+                allocated.push(rg);
+            }
+            // Every byte is either still free or allocated, exactly once.
+            let mut all: Vec<Range> = rs.iter().cloned().chain(allocated.iter().cloned()).collect();
+            all.sort_by_key(|rg| rg.start);
+            let mut merged: Vec<Range> = Vec::new();
+            for rg in all {
+                assert!(rg.len() != 0);
+                match merged.last_mut() {
+                    Some(last) if last.end == rg.start => last.end = rg.end,
+                    Some(last) => {
+                        assert!(last.end < rg.start);
+                        merged.push(rg);
+                    }
+                    None => merged.push(rg),
+                }
+            }
+            assert_eq!(merged, original);
+        }
+        assert!(!allocated.is_empty());
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+        use std::collections::BTreeSet;
+        use std::vec::Vec;
+
+        #[derive(Clone, Debug)]
+        enum Op {
+            Insert(Range),
+            Subtract(Range),
+        }
+
+        fn range() -> impl Strategy<Value = Range> {
+            (0usize..128, 0usize..32).prop_map(|(start, len)| Range::new(start, start + len))
+        }
+
+        fn op() -> impl Strategy<Value = Op> {
+            prop_oneof![range().prop_map(Op::Insert), range().prop_map(Op::Subtract)]
+        }
+
+        /// Apply the ops to a RangeSet and to a set of addresses.
+        fn build(ops: &[Op]) -> (RangeSet, BTreeSet<usize>) {
+            let mut rs = RangeSet::empty();
+            let mut model = BTreeSet::new();
+            for op in ops {
+                match op {
+                    Op::Insert(rg) => {
+                        rs.insert(rg.clone());
+                        model.extend(rg.start..rg.end);
+                    }
+                    Op::Subtract(rg) => {
+                        rs.subtract(rg);
+                        for addr in rg.start..rg.end {
+                            model.remove(&addr);
+                        }
+                    }
+                }
+                check(&rs, &model);
+            }
+            (rs, model)
+        }
+
+        /// The set is sorted, fully coalesced, and holds exactly the model.
+        fn check(rs: &RangeSet, model: &BTreeSet<usize>) {
+            for rg in rs.iter() {
+                assert!(rg.start < rg.end);
+            }
+            for pair in rs.set.windows(2) {
+                assert!(pair[0].end < pair[1].start, "{:?} not coalesced", pair);
+            }
+            let addrs: BTreeSet<usize> = rs.iter().flat_map(|rg| rg.start..rg.end).collect();
+            assert_eq!(&addrs, model);
+        }
+
+        proptest! {
+            #[test]
+            fn insert_subtract_match_model(ops in vec(op(), 0..64)) {
+                build(&ops);
+            }
+
+            #[test]
+            fn union_intersect_match_model(a in vec(op(), 0..32), b in vec(op(), 0..32)) {
+                let (rs_a, model_a) = build(&a);
+                let (rs_b, model_b) = build(&b);
+
+                let mut union = RangeSet::empty();
+                union.union(&rs_a);
+                union.union(&rs_b);
+                check(&union, &model_a.union(&model_b).cloned().collect());
+
+                let mut intersection = RangeSet::empty();
+                intersection.union(&rs_a);
+                intersection.intersect(&rs_b);
+                check(&intersection, &model_a.intersection(&model_b).cloned().collect());
+            }
+
+            #[test]
+            fn contains_matches_model(ops in vec(op(), 0..32)) {
+                let (rs, model) = build(&ops);
+                for addr in 0..170 {
+                    prop_assert_eq!(rs.contains(addr), model.contains(&addr));
+                }
+                let starts: Vec<usize> = rs.iter().map(|rg| rg.start).collect();
+                prop_assert!(starts.windows(2).all(|w| w[0] < w[1]));
+            }
+        }
+    }
 }
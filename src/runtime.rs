@@ -1,6 +1,7 @@
 #![cfg(not(test))]
 use crate::log;
 use crate::logger;
+use crate::mutex;
 use crate::debug;
 use crate::interrupts;
 use core::panic::PanicInfo;
@@ -16,13 +17,21 @@ pub fn panic(info: &PanicInfo) -> ! {
     // Disable interrupts.
     interrupts::disable();
 
+    // Locks this hart holds will never be released. Marking it lets harts
+    // waiting on them, or this one when it logs, take them over as poisoned.
+    mutex::mark_panicked();
+
     // Prevent recursive panics or concurrent panics on several cores.
     if !HAVE_PANICKED.compare_and_swap(false, true, atomic::Ordering::SeqCst) {
-        unsafe {
-            logger::LOGGER.bust_lock();
-        }
+        let logger_owner = logger::LOGGER.get().and_then(|logger| logger.owner());
 
         log!("PANIC: {:#?} {:#?}\n", info.message(), info.location());
+        if let Some(owner) = logger_owner {
+            log!("LOGGER held by {}", owner);
+        }
+        // Logging only writes out if the UART is free, and its holder may
+        // never free it now.
+        logger::sync();
         // debug::break_point();
     }
 
@@ -0,0 +1,411 @@
+/// Timer interrupts and the time.
+/// The time counts up at the `timebase-frequency` from the device tree. Each
+/// hart keeps a queue of timers and programs the earliest deadline into the
+/// hardware, either the CLINT when the kernel owns machine mode, or through
+/// the SBI TIME extension when firmware does. Callbacks run in the timer
+/// interrupt on the hart which added them, so they mustn't take long.
+use crate::log;
+use crate::device_tree::DeviceTree;
+use crate::hart::{self, MAX_HARTS};
+use crate::trap::{self, InterruptCause, Trap, TrapAction, TrapContext, TrapHandler};
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use mutex::{IrqControl, IrqMutex, SeqLock, Sie};
+
+/// Matches MACHINE_MODE_START in start.S, the Makefile sets both. When set,
+/// start.S forwards machine timer interrupts as supervisor software
+/// interrupts.
+const MACHINE_MODE_START: bool = !cfg!(feature = "sbi");
+
+/// Where qemu virt has the CLINT.
+const VIRT_CLINT: usize = 0x200_0000;
+
+/// CLINT register offsets.
+const CLINT_MTIMECMP: usize = 0x4000;
+const CLINT_MTIME: usize = 0xbff8;
+
+/// The SBI TIME extension and its set_timer function.
+const SBI_EXT_TIME: usize = 0x5449_4d45;
+const SBI_SET_TIMER: usize = 0;
+
+/// Supervisor software interrupt pending.
+const SIP_SSIP: usize = 1 << 1;
+
+/// Timers a hart can have at once.
+const MAX_TIMERS: usize = 16;
+
+/// Where start.S finds the CLINT. It has to quiet the timer before the
+/// device tree has been read, so until `init` this is a guess.
+#[no_mangle]
+static CLINT_BASE: AtomicUsize = AtomicUsize::new(VIRT_CLINT);
+
+/// Each hart's scratch space for start.S's machine timer handler, which
+/// mscratch points at. start.S knows this layout.
+#[repr(C, align(32))]
+struct MtimerScratch {
+    save: [AtomicUsize; 2],
+    /// Set when the handler raises a supervisor software interrupt for the
+    /// timer, rather than somebody sending an IPI.
+    forwarded: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SCRATCH: MtimerScratch = MtimerScratch {
+    save: [AtomicUsize::new(0), AtomicUsize::new(0)],
+    forwarded: AtomicUsize::new(0),
+};
+#[no_mangle]
+static MTIMER_SCRATCH: [MtimerScratch; MAX_HARTS] = [EMPTY_SCRATCH; MAX_HARTS];
+
+/// The IPI handler which the supervisor software interrupt is passed on to.
+static IPI_HANDLER: SeqLock<Option<TrapHandler>> = SeqLock::new(None);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Source {
+    /// Not set up yet.
+    None,
+    /// The CLINT at this address.
+    Clint(usize),
+    Sbi,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Clock {
+    source: Source,
+    /// Ticks per second.
+    frequency: u64,
+}
+
+static CLOCK: SeqLock<Clock> = SeqLock::new(Clock {
+    source: Source::None,
+    frequency: 0,
+});
+
+/// Called with the time when the timer fires.
+pub type TimerCallback = fn(now: u64);
+
+/// Names a timer so it can be cancelled.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimerId {
+    hart: usize,
+    seq: u64,
+}
+
+#[derive(Copy, Clone)]
+struct Timer {
+    seq: u64,
+    deadline: u64,
+    /// Zero for one shot timers.
+    period: u64,
+    callback: TimerCallback,
+}
+
+struct Queue {
+    timers: [Option<Timer>; MAX_TIMERS],
+    next_seq: u64,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Self {
+            timers: [None; MAX_TIMERS],
+            next_seq: 0,
+        }
+    }
+
+    fn add(&mut self, deadline: u64, period: u64, callback: TimerCallback) -> Option<u64> {
+        let slot = self.timers.iter_mut().find(|t| t.is_none())?;
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        *slot = Some(Timer {
+            seq,
+            deadline,
+            period,
+            callback,
+        });
+        Some(seq)
+    }
+
+    fn cancel(&mut self, seq: u64) -> bool {
+        match self.timers.iter_mut().find(|t| t.map(|t| t.seq) == Some(seq)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        self.timers.iter().flatten().map(|t| t.deadline).min()
+    }
+
+    /// Collect the callbacks of timers due by `now` into `fired`, returning
+    /// how many. One shot timers are removed, periodic ones move on to their
+    /// next deadline. Ticks missed while interrupts were off are skipped
+    /// rather than delivered late in a burst.
+    fn expire(&mut self, now: u64, fired: &mut [Option<TimerCallback>; MAX_TIMERS]) -> usize {
+        let mut count = 0;
+        for slot in self.timers.iter_mut() {
+            let timer = match slot {
+                Some(timer) if timer.deadline <= now => timer,
+                _ => continue,
+            };
+            fired[count] = Some(timer.callback);
+            count += 1;
+            // One shot timers have no period to divide by.
+            match (now - timer.deadline).checked_div(timer.period) {
+                Some(missed) => {
+                    let next = (missed + 1).saturating_mul(timer.period);
+                    timer.deadline = timer.deadline.saturating_add(next);
+                }
+                None => *slot = None,
+            }
+        }
+        count
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_QUEUE: IrqMutex<Queue> = IrqMutex::new(Queue::new());
+static QUEUES: [IrqMutex<Queue>; MAX_HARTS] = [EMPTY_QUEUE; MAX_HARTS];
+
+/// Only the kernel can use the CLINT, and only if it owns machine mode.
+/// Otherwise firmware does and we ask it.
+fn choose_source(clint: Option<usize>, machine_mode: bool) -> Source {
+    match clint {
+        Some(base) if machine_mode => Source::Clint(base),
+        _ => Source::Sbi,
+    }
+}
+
+/// Find the timebase and the timer hardware, and take the timer interrupts.
+/// With the CLINT, timer interrupts share the supervisor software interrupt
+/// with IPIs, which are passed on to the handler registered for it before
+/// this. The IPI handler must clear sip.SSIP, and expect to be called when
+/// there is no IPI.
+pub fn init(device_tree: &DeviceTree) {
+    let frequency = device_tree
+        .timebase_frequency()
+        .expect("timebase-frequency not found in device tree");
+    let clint = device_tree.find_regs("clint").map(|(base, _)| base);
+    let source = choose_source(clint, MACHINE_MODE_START);
+    if let Source::Clint(base) = source {
+        CLINT_BASE.store(base, Ordering::Release);
+        // A software interrupt in between would find no IPI handler.
+        let was_enabled = Sie::disable();
+        let ipi = trap::register(
+            Trap::Interrupt(InterruptCause::SupervisorSoftware),
+            software_interrupt,
+        );
+        IPI_HANDLER.write(ipi);
+        Sie::restore(was_enabled);
+    }
+    CLOCK.write(Clock { source, frequency });
+    trap::register(
+        Trap::Interrupt(InterruptCause::SupervisorTimer),
+        timer_interrupt,
+    );
+    // start.S may have guessed the CLINT wrong.
+    program(hart::id(), None);
+    log!("Timer: {:?} at {} Hz", source, frequency);
+}
+
+/// Ticks per second.
+pub fn frequency() -> u64 {
+    CLOCK.read().frequency
+}
+
+/// The time in ticks.
+pub fn now() -> u64 {
+    match CLOCK.read().source {
+        Source::None => 0,
+        Source::Clint(base) => unsafe { ((base + CLINT_MTIME) as *const u64).read_volatile() },
+        Source::Sbi => {
+            let time: u64;
+            unsafe { asm!("rdtime $0" : "=r"(time) ::: "volatile"); }
+            time
+        }
+    }
+}
+
+fn to_ticks(duration: Duration, frequency: u64) -> u64 {
+    let ticks = duration.as_nanos().saturating_mul(frequency as u128) / 1_000_000_000;
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+/// Convert a duration to ticks, rounding down. Durations too long to count
+/// come out as u64::MAX, which is never.
+pub fn ticks(duration: Duration) -> u64 {
+    to_ticks(duration, frequency())
+}
+
+/// Call `callback` once, after `delay`. Returns None if this hart has too
+/// many timers.
+pub fn one_shot(delay: Duration, callback: TimerCallback) -> Option<TimerId> {
+    add(ticks(delay), 0, callback)
+}
+
+/// Call `callback` every `period`, starting one period from now. Returns None
+/// if this hart has too many timers.
+pub fn periodic(period: Duration, callback: TimerCallback) -> Option<TimerId> {
+    let period = ticks(period).max(1);
+    add(period, period, callback)
+}
+
+fn add(delay: u64, period: u64, callback: TimerCallback) -> Option<TimerId> {
+    let hart = hart::id();
+    let mut queue = QUEUES[hart].lock();
+    let seq = queue.add(now().saturating_add(delay), period, callback)?;
+    program(hart, queue.next_deadline());
+    Some(TimerId { hart, seq })
+}
+
+/// Stop a timer. Returns false if it already fired, or was cancelled.
+pub fn cancel(id: TimerId) -> bool {
+    let mut queue = QUEUES[id.hart].lock();
+    let cancelled = queue.cancel(id.seq);
+    // Another hart's deadline is left be, it will just find nothing due.
+    if cancelled && id.hart == hart::id() {
+        program(id.hart, queue.next_deadline());
+    }
+    cancelled
+}
+
+/// Set the hart's next timer interrupt, or turn it off.
+fn program(hart: usize, deadline: Option<u64>) {
+    let deadline = deadline.unwrap_or(u64::MAX);
+    match CLOCK.read().source {
+        Source::None => {}
+        Source::Clint(base) => unsafe {
+            ((base + CLINT_MTIMECMP + hart * 8) as *mut u64).write_volatile(deadline)
+        },
+        Source::Sbi => sbi_set_timer(deadline),
+    }
+}
+
+fn sbi_set_timer(deadline: u64) {
+    let error: isize;
+    unsafe {
+        asm!("ecall"
+             : "={a0}"(error)
+             : "{a0}"(deadline), "{a6}"(SBI_SET_TIMER), "{a7}"(SBI_EXT_TIME)
+             : "a1", "memory"
+             : "volatile");
+    }
+    assert_eq!(error, 0, "SBI set_timer failed");
+}
+
+/// Run the callbacks which are due and program the next deadline.
+fn expire() {
+    let hart = hart::id();
+    let now = now();
+    let mut fired = [None; MAX_TIMERS];
+    let count = {
+        let mut queue = QUEUES[hart].lock();
+        let count = queue.expire(now, &mut fired);
+        program(hart, queue.next_deadline());
+        count
+    };
+    // Not holding the lock, so callbacks may add and cancel timers.
+    for callback in fired[..count].iter().flatten() {
+        callback(now);
+    }
+}
+
+fn timer_interrupt(_trapctx: &mut TrapContext) -> TrapAction {
+    expire();
+    TrapAction::Resume
+}
+
+/// start.S turns CLINT timer interrupts into supervisor software interrupts,
+/// which may also be IPIs.
+fn software_interrupt(trapctx: &mut TrapContext) -> TrapAction {
+    let action = match IPI_HANDLER.read() {
+        Some(ipi) => ipi(trapctx),
+        None => {
+            unsafe { asm!("csrc sip, $0" :: "r"(SIP_SSIP) :: "volatile"); }
+            TrapAction::Resume
+        }
+    };
+    // Check once the pending bit is clear, so a timer interrupt forwarded
+    // meanwhile raises it again rather than going unnoticed.
+    if MTIMER_SCRATCH[hart::id()].forwarded.swap(0, Ordering::AcqRel) != 0 {
+        expire();
+    }
+    action
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    fn tick(_: u64) {}
+
+    #[test]
+    fn duration_to_ticks() {
+        let frequency = 10_000_000;
+        assert_eq!(to_ticks(Duration::from_secs(1), frequency), frequency);
+        assert_eq!(to_ticks(Duration::from_millis(10), frequency), 100_000);
+        assert_eq!(to_ticks(Duration::from_nanos(99), frequency), 0);
+        // Too long to count means never.
+        assert_eq!(to_ticks(Duration::MAX, frequency), u64::MAX);
+        assert_eq!(to_ticks(Duration::from_secs(u64::MAX / 2), frequency), u64::MAX);
+    }
+
+    #[test]
+    fn sources() {
+        assert_eq!(choose_source(Some(VIRT_CLINT), true), Source::Clint(VIRT_CLINT));
+        // Firmware owns the CLINT.
+        assert_eq!(choose_source(Some(VIRT_CLINT), false), Source::Sbi);
+        assert_eq!(choose_source(None, true), Source::Sbi);
+    }
+
+    #[test]
+    fn scratch_layout() {
+        // Offsets start.S uses.
+        let scratch = &MTIMER_SCRATCH[1];
+        let base = &MTIMER_SCRATCH[0] as *const _ as usize;
+        assert_eq!(scratch as *const _ as usize - base, 32);
+        let forwarded = &scratch.forwarded as *const _ as usize;
+        assert_eq!(forwarded - scratch as *const _ as usize, 16);
+    }
+
+    #[test]
+    fn queue() {
+        let mut queue = Queue::new();
+        let mut fired = [None; MAX_TIMERS];
+        assert_eq!(queue.next_deadline(), None);
+        let once = queue.add(100, 0, tick).unwrap();
+        let every = queue.add(50, 50, tick).unwrap();
+        assert_eq!(queue.next_deadline(), Some(50));
+        assert_eq!(queue.expire(49, &mut fired), 0);
+        assert_eq!(queue.expire(50, &mut fired), 1);
+        assert_eq!(queue.next_deadline(), Some(100));
+        // Both are due, and the periodic timer skips the ticks it missed.
+        assert_eq!(queue.expire(260, &mut fired), 2);
+        assert_eq!(queue.next_deadline(), Some(300));
+        assert!(!queue.cancel(once));
+        assert!(queue.cancel(every));
+        assert_eq!(queue.next_deadline(), None);
+        // A period too long to reach stops at never rather than wrapping.
+        let forever = queue.add(10, u64::MAX, tick).unwrap();
+        assert_eq!(queue.expire(10, &mut fired), 1);
+        assert_eq!(queue.next_deadline(), Some(u64::MAX));
+        assert!(queue.cancel(forever));
+    }
+
+    #[test]
+    fn full_queue() {
+        let mut queue = Queue::new();
+        for _ in 0..MAX_TIMERS {
+            assert!(queue.add(1, 0, tick).is_some());
+        }
+        assert!(queue.add(1, 0, tick).is_none());
+        let mut fired = [None; MAX_TIMERS];
+        assert_eq!(queue.expire(1, &mut fired), MAX_TIMERS);
+        assert!(queue.add(1, 0, tick).is_some());
+    }
+}
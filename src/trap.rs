@@ -1,18 +1,107 @@
 use crate::log;
-use crate::interrupts;
+use crate::constants::PAGE_SIZE;
+use crate::hart::MAX_HARTS;
+use crate::mutex::SeqLock;
+use core::fmt;
 use core::mem;
 
-#[derive(Copy, Clone, Debug, Default)]
+/// The most vector register state a context has room for, VLEN 256.
+/// Matches MAX_VLENB in trap.S.
+pub const MAX_VLENB: usize = 32;
+
+/// sstatus.FS and sstatus.VS say whether the FP and vector registers are off,
+/// initial, clean or dirty.
+const SSTATUS_FS: usize = 0b11 << 13;
+const SSTATUS_FS_CLEAN: usize = 0b10 << 13;
+const SSTATUS_VS: usize = 0b11 << 9;
+const SSTATUS_VS_CLEAN: usize = 0b10 << 9;
+
+/// State saved on a trap. trap.S knows this layout.
+#[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct TrapContext {
-    regs: [usize; 32],
+    pub regs: [usize; 32],
     trap_sp: usize,
     hartid: usize,
     satp: usize,
+    /// Where the trap returns to.
+    pub sepc: usize,
+    pub sstatus: usize,
+    pub stval: usize,
+    pub scause: usize,
+    fp: FpState,
+    vector: VectorState,
+}
+
+/// The F and D registers. Only saved when the trapped code dirtied them.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct FpState {
+    pub f: [u64; 32],
+    pub fcsr: usize,
+    // Set to load the registers on the way out of the trap.
+    restore: usize,
 }
 
+/// The V registers, if the hart has them. Only saved when the trapped code
+/// dirtied them.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct VectorState {
+    pub vstart: usize,
+    pub vl: usize,
+    pub vtype: usize,
+    pub vcsr: usize,
+    /// The size of one register in bytes.
+    pub vlenb: usize,
+    // Set to load the registers on the way out of the trap.
+    restore: usize,
+    /// v0 to v31, each vlenb bytes.
+    pub regs: [u8; 32 * MAX_VLENB],
+}
+
+/// Each hart's trap context. sscratch points at the hart's own while it runs
+/// in the kernel.
+static mut TRAP_CONTEXTS: [TrapContext; MAX_HARTS] = [TrapContext::empty(); MAX_HARTS];
+
+/// Traps are handled on a separate stack per hart, so a trap taken with a bad
+/// stack pointer can still be reported.
+const TRAP_STACK_SIZE: usize = 4 * PAGE_SIZE;
+
+#[derive(Copy, Clone)]
+#[repr(C, align(16))]
+struct TrapStack([u8; TRAP_STACK_SIZE]);
+
+static mut TRAP_STACKS: [TrapStack; MAX_HARTS] = [TrapStack([0; TRAP_STACK_SIZE]); MAX_HARTS];
+
+/// Set up the trap context and stack of the given hart, or None if there are
+/// too many harts.
+unsafe fn prepare_context(hartid: usize) -> Option<*mut TrapContext> {
+    if hartid >= MAX_HARTS {
+        return None;
+    }
+    let ctx = &mut TRAP_CONTEXTS[hartid];
+    let stack = &mut TRAP_STACKS[hartid];
+    *ctx = TrapContext::empty();
+    ctx.hartid = hartid;
+    ctx.trap_sp = stack.0.as_mut_ptr().add(TRAP_STACK_SIZE) as usize;
+    Some(ctx)
+}
+
+/// Called by start.S on each hart before it enters the kernel, to point
+/// sscratch at the hart's trap context. Returns false if the hart has no
+/// context and must be parked.
+#[cfg(not(test))]
 #[no_mangle]
-pub static mut BOOTSTRAP_CORE_TRAP_CONTEXT: TrapContext = TrapContext::empty();
+pub unsafe extern "C" fn trap_init_hart(hartid: usize) -> bool {
+    match prepare_context(hartid) {
+        Some(ctx) => {
+            asm!("csrw sscratch, $0" :: "r"(ctx) :: "volatile");
+            true
+        }
+        None => false,
+    }
+}
 
 impl TrapContext {
     const fn empty() -> Self {
@@ -21,110 +110,618 @@ impl TrapContext {
             satp: 0,
             trap_sp: 0,
             hartid: 0,
+            sepc: 0,
+            sstatus: 0,
+            stval: 0,
+            scause: 0,
+            fp: FpState {
+                f: [0; 32],
+                fcsr: 0,
+                restore: 0,
+            },
+            vector: VectorState {
+                vstart: 0,
+                vl: 0,
+                vtype: 0,
+                vcsr: 0,
+                vlenb: 0,
+                restore: 0,
+                regs: [0; 32 * MAX_VLENB],
+            },
+        }
+    }
+
+    /// The hart which took the trap.
+    pub fn hartid(&self) -> usize {
+        self.hartid
+    }
+
+    /// The trapped code's FP registers, if it has used them.
+    pub fn fp(&self) -> Option<&FpState> {
+        if self.sstatus & SSTATUS_FS == 0 {
+            None
+        } else {
+            Some(&self.fp)
+        }
+    }
+
+    /// Change the trapped code's FP registers. They're loaded when the trap
+    /// returns.
+    pub fn fp_mut(&mut self) -> &mut FpState {
+        self.fp.restore = 1;
+        self.sstatus = (self.sstatus & !SSTATUS_FS) | SSTATUS_FS_CLEAN;
+        &mut self.fp
+    }
+
+    /// The trapped code's vector registers, if it has used them.
+    pub fn vector(&self) -> Option<&VectorState> {
+        if self.sstatus & SSTATUS_VS == 0 {
+            None
+        } else {
+            Some(&self.vector)
         }
     }
+
+    /// Change the trapped code's vector registers. They're loaded when the
+    /// trap returns.
+    pub fn vector_mut(&mut self) -> &mut VectorState {
+        self.vector.restore = 1;
+        self.sstatus = (self.sstatus & !SSTATUS_VS) | SSTATUS_VS_CLEAN;
+        &mut self.vector
+    }
+}
+
+/// Why a trap was taken, decoded from scause.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Trap {
+    Interrupt(InterruptCause),
+    Exception(ExceptionCause),
 }
 
-const INTERRUPT_TYPE_MASK: usize = 1 << ((mem::size_of::<usize>() - 1) * 8);
-const USER_SOFTWARE_INTERRUPT: usize = 0;
-const SUPERVISOR_SOFTWARE_INTERRUPT: usize = 1;
-const MACHINE_SOFTWARE_INTERRUPT: usize = 3;
-const USER_TIMER_INTERRUPT: usize = 4;
-const SUPERVISOR_TIMER_INTERRUPT: usize = 5;
-const MACHINE_TIMER_INTERRUPT: usize = 7;
-const USER_EXTERNAL_INTERRUPT: usize = 8;
-const SUPERVISOR_EXTERNAL_INTERRUPT: usize = 9;
-const MACHINE_EXTERNAL_INTERRUPT: usize = 11;
-
-const INSTRUCTION_ADDR_MISALIGNED: usize = INTERRUPT_TYPE_MASK;
-const INSTRUCTION_ACCESS_FAULT: usize = 1 | INTERRUPT_TYPE_MASK;
-const ILLEGAL_INSTRUCTION: usize = 2 | INTERRUPT_TYPE_MASK;
-const BREAKPOINT: usize = 3 | INTERRUPT_TYPE_MASK;
-const LOAD_ADDRESS_MISALIGNED: usize = 4 | INTERRUPT_TYPE_MASK;
-const LOAD_ACCESS_FAULT: usize = 5 | INTERRUPT_TYPE_MASK;
-const STORE_AMO_ADDRESS_MISALIGNED: usize = 6 | INTERRUPT_TYPE_MASK;
-const STORE_AMO_ACCESS_FAULT: usize = 7 | INTERRUPT_TYPE_MASK;
-const ECALL_FROM_UMODE: usize = 8 | INTERRUPT_TYPE_MASK;
-const ECALL_FROM_SMODE: usize = 9 | INTERRUPT_TYPE_MASK;
-const ECALL_FROM_MMODE: usize = 11 | INTERRUPT_TYPE_MASK;
-const INSTRUCTION_PAGE_FAULT: usize = 12 | INTERRUPT_TYPE_MASK;
-const LOAD_PAGE_FAULT: usize = 13 | INTERRUPT_TYPE_MASK;
-const STORE_AMO_PAGE_FAULT: usize = 15 | INTERRUPT_TYPE_MASK;
-
-fn trap_reason(mcause: usize) -> &'static str {
-    match mcause {
-        USER_SOFTWARE_INTERRUPT => "User software interrupt",
-        SUPERVISOR_SOFTWARE_INTERRUPT => "Supervisor software interrupt",
-        MACHINE_SOFTWARE_INTERRUPT => "Machine software interrupt",
-        USER_TIMER_INTERRUPT => "User timer interrupt",
-        SUPERVISOR_TIMER_INTERRUPT => "Supervisor timer interrupt",
-        MACHINE_TIMER_INTERRUPT => "Machine timer interrupt",
-        USER_EXTERNAL_INTERRUPT => "User external interrupt",
-        SUPERVISOR_EXTERNAL_INTERRUPT => "Supervisor external interrupt",
-        MACHINE_EXTERNAL_INTERRUPT => "Machine external interrupt",
-
-        INSTRUCTION_ADDR_MISALIGNED => "Instruction address misaligned",
-        INSTRUCTION_ACCESS_FAULT => "Instruction access fault",
-        ILLEGAL_INSTRUCTION => "Illegal instruction",
-        BREAKPOINT => "Breakpoint",
-        LOAD_ADDRESS_MISALIGNED => "Load address misaligned",
-        LOAD_ACCESS_FAULT => "Load access fault",
-        STORE_AMO_ADDRESS_MISALIGNED => "Store AMO address misaligned",
-        STORE_AMO_ACCESS_FAULT => "Store AMO access fault",
-        ECALL_FROM_UMODE => "ECall from UMode",
-        ECALL_FROM_SMODE => "ECall from SMode",
-        ECALL_FROM_MMODE => "ECall from MMode",
-        INSTRUCTION_PAGE_FAULT => "Instruction page fault",
-        LOAD_PAGE_FAULT => "Load page fault",
-        STORE_AMO_PAGE_FAULT => "Store AMO page fault",
-        _ => "Unknown reason",
+/// Interrupt codes from the privileged spec.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InterruptCause {
+    UserSoftware,
+    SupervisorSoftware,
+    MachineSoftware,
+    UserTimer,
+    SupervisorTimer,
+    MachineTimer,
+    UserExternal,
+    SupervisorExternal,
+    MachineExternal,
+    CounterOverflow,
+    /// Reserved or platform specific.
+    Unknown(usize),
+}
+
+/// Exception codes from the privileged spec.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExceptionCause {
+    InstructionAddressMisaligned,
+    InstructionAccessFault,
+    IllegalInstruction,
+    Breakpoint,
+    LoadAddressMisaligned,
+    LoadAccessFault,
+    StoreAmoAddressMisaligned,
+    StoreAmoAccessFault,
+    EcallFromUMode,
+    EcallFromSMode,
+    EcallFromMMode,
+    InstructionPageFault,
+    LoadPageFault,
+    StoreAmoPageFault,
+    SoftwareCheck,
+    HardwareError,
+    /// Reserved or custom.
+    Unknown(usize),
+}
+
+impl InterruptCause {
+    fn from_code(code: usize) -> Self {
+        use InterruptCause::*;
+        match code {
+            0 => UserSoftware,
+            1 => SupervisorSoftware,
+            3 => MachineSoftware,
+            4 => UserTimer,
+            5 => SupervisorTimer,
+            7 => MachineTimer,
+            8 => UserExternal,
+            9 => SupervisorExternal,
+            11 => MachineExternal,
+            13 => CounterOverflow,
+            code => Unknown(code),
+        }
+    }
+
+    fn code(self) -> usize {
+        use InterruptCause::*;
+        match self {
+            UserSoftware => 0,
+            SupervisorSoftware => 1,
+            MachineSoftware => 3,
+            UserTimer => 4,
+            SupervisorTimer => 5,
+            MachineTimer => 7,
+            UserExternal => 8,
+            SupervisorExternal => 9,
+            MachineExternal => 11,
+            CounterOverflow => 13,
+            Unknown(code) => code,
+        }
+    }
+}
+
+impl ExceptionCause {
+    fn from_code(code: usize) -> Self {
+        use ExceptionCause::*;
+        match code {
+            0 => InstructionAddressMisaligned,
+            1 => InstructionAccessFault,
+            2 => IllegalInstruction,
+            3 => Breakpoint,
+            4 => LoadAddressMisaligned,
+            5 => LoadAccessFault,
+            6 => StoreAmoAddressMisaligned,
+            7 => StoreAmoAccessFault,
+            8 => EcallFromUMode,
+            9 => EcallFromSMode,
+            11 => EcallFromMMode,
+            12 => InstructionPageFault,
+            13 => LoadPageFault,
+            15 => StoreAmoPageFault,
+            18 => SoftwareCheck,
+            19 => HardwareError,
+            code => Unknown(code),
+        }
+    }
+
+    fn code(self) -> usize {
+        use ExceptionCause::*;
+        match self {
+            InstructionAddressMisaligned => 0,
+            InstructionAccessFault => 1,
+            IllegalInstruction => 2,
+            Breakpoint => 3,
+            LoadAddressMisaligned => 4,
+            LoadAccessFault => 5,
+            StoreAmoAddressMisaligned => 6,
+            StoreAmoAccessFault => 7,
+            EcallFromUMode => 8,
+            EcallFromSMode => 9,
+            EcallFromMMode => 11,
+            InstructionPageFault => 12,
+            LoadPageFault => 13,
+            StoreAmoPageFault => 15,
+            SoftwareCheck => 18,
+            HardwareError => 19,
+            Unknown(code) => code,
+        }
+    }
+}
+
+impl Trap {
+    /// Decode a cause register read on a hart with the given XLEN. The top
+    /// bit says whether it's an interrupt, the rest is the code.
+    pub fn from_cause(cause: u64, xlen: u32) -> Self {
+        let interrupt = 1 << (xlen - 1);
+        let code = (cause & !interrupt) as usize;
+        if cause & interrupt != 0 {
+            Trap::Interrupt(InterruptCause::from_code(code))
+        } else {
+            Trap::Exception(ExceptionCause::from_code(code))
+        }
+    }
+
+    /// Decode scause on this hart.
+    pub fn from_scause(scause: usize) -> Self {
+        Self::from_cause(scause as u64, mem::size_of::<usize>() as u32 * 8)
+    }
+
+    fn description(self) -> &'static str {
+        use ExceptionCause::*;
+        use InterruptCause::*;
+        match self {
+            Trap::Interrupt(cause) => match cause {
+                UserSoftware => "User software interrupt",
+                SupervisorSoftware => "Supervisor software interrupt",
+                MachineSoftware => "Machine software interrupt",
+                UserTimer => "User timer interrupt",
+                SupervisorTimer => "Supervisor timer interrupt",
+                MachineTimer => "Machine timer interrupt",
+                UserExternal => "User external interrupt",
+                SupervisorExternal => "Supervisor external interrupt",
+                MachineExternal => "Machine external interrupt",
+                CounterOverflow => "Counter overflow interrupt",
+                InterruptCause::Unknown(_) => "Unknown interrupt",
+            },
+            Trap::Exception(cause) => match cause {
+                InstructionAddressMisaligned => "Instruction address misaligned",
+                InstructionAccessFault => "Instruction access fault",
+                IllegalInstruction => "Illegal instruction",
+                Breakpoint => "Breakpoint",
+                LoadAddressMisaligned => "Load address misaligned",
+                LoadAccessFault => "Load access fault",
+                StoreAmoAddressMisaligned => "Store AMO address misaligned",
+                StoreAmoAccessFault => "Store AMO access fault",
+                EcallFromUMode => "ECall from UMode",
+                EcallFromSMode => "ECall from SMode",
+                EcallFromMMode => "ECall from MMode",
+                InstructionPageFault => "Instruction page fault",
+                LoadPageFault => "Load page fault",
+                StoreAmoPageFault => "Store AMO page fault",
+                SoftwareCheck => "Software check",
+                HardwareError => "Hardware error",
+                ExceptionCause::Unknown(_) => "Unknown exception",
+            },
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+/// What to do once a trap has been handled.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TrapAction {
+    /// Return to the trapping instruction, e.g. after an interrupt or a fixed
+    /// up page fault.
+    Resume,
+    /// Return to the instruction after the trapping one, e.g. after an ecall
+    /// or a breakpoint.
+    Skip,
+    /// The current task can't go on. There are no tasks yet, so this panics.
+    Kill,
+}
+
+pub type TrapHandler = fn(&mut TrapContext) -> TrapAction;
+
+/// Codes past this have no handlers. The privileged spec's codes stop at 19,
+/// higher ones are reserved or custom.
+const MAX_CAUSE: usize = 20;
+
+#[derive(Copy, Clone)]
+struct Handlers {
+    interrupts: [Option<TrapHandler>; MAX_CAUSE],
+    exceptions: [Option<TrapHandler>; MAX_CAUSE],
+}
+
+impl Handlers {
+    fn slot(&mut self, trap: Trap) -> Option<&mut Option<TrapHandler>> {
+        match trap {
+            Trap::Interrupt(cause) => self.interrupts.get_mut(cause.code()),
+            Trap::Exception(cause) => self.exceptions.get_mut(cause.code()),
+        }
+    }
+}
+
+/// Read on every trap, and written as subsystems come up. A sequence lock so
+/// a trap never waits on a registration.
+static HANDLERS: SeqLock<Handlers> = SeqLock::new(Handlers {
+    interrupts: [None; MAX_CAUSE],
+    exceptions: [None; MAX_CAUSE],
+});
+
+/// Handle the given trap, returning the old handler.
+pub fn register(trap: Trap, handler: TrapHandler) -> Option<TrapHandler> {
+    // Check before updating, panicking mid update would leave every later
+    // read spinning.
+    if HANDLERS.read().slot(trap).is_none() {
+        panic!("Can't handle {:?}", trap);
+    }
+    let mut old = None;
+    HANDLERS.update(|handlers| {
+        if let Some(slot) = handlers.slot(trap) {
+            old = slot.replace(handler);
+        }
+    });
+    old
+}
+
+/// Install the handlers for traps the kernel handles itself. Subsystems
+/// register the rest, or replace these, as they come up.
+pub fn init() {
+    use ExceptionCause::*;
+    register(Trap::Interrupt(InterruptCause::SupervisorSoftware), ipi);
+    register(Trap::Exception(Breakpoint), breakpoint);
+    register(Trap::Exception(IllegalInstruction), illegal_instruction);
+    register(Trap::Exception(EcallFromUMode), ecall);
+    register(Trap::Exception(EcallFromSMode), ecall);
+    for &fault in &[InstructionPageFault, LoadPageFault, StoreAmoPageFault] {
+        register(Trap::Exception(fault), page_fault);
+    }
+}
+
+/// Supervisor software interrupt pending.
+const SIP_SSIP: usize = 1 << 1;
+
+/// Nothing sends IPIs yet, so just acknowledge them.
+fn ipi(_trapctx: &mut TrapContext) -> TrapAction {
+    unsafe { asm!("csrc sip, $0" :: "r"(SIP_SSIP) :: "volatile"); }
+    TrapAction::Resume
+}
+
+fn breakpoint(trapctx: &mut TrapContext) -> TrapAction {
+    log!("Breakpoint on hart {} at {:x}", trapctx.hartid, trapctx.sepc);
+    TrapAction::Skip
+}
+
+fn illegal_instruction(trapctx: &mut TrapContext) -> TrapAction {
+    log!(
+        "Illegal instruction {:x} at {:x}",
+        trapctx.stval, trapctx.sepc
+    );
+    TrapAction::Kill
+}
+
+/// There are no system calls yet. Fail them like an unsupported SBI call,
+/// with the error in a0.
+const ECALL_NOT_SUPPORTED: isize = -2;
+
+fn ecall(trapctx: &mut TrapContext) -> TrapAction {
+    log!(
+        "Unsupported ecall {:x} at {:x}",
+        trapctx.regs[17], trapctx.sepc
+    );
+    trapctx.regs[10] = ECALL_NOT_SUPPORTED as usize;
+    TrapAction::Skip
+}
+
+/// Nothing is paged yet, so any page fault is a bug.
+fn page_fault(trapctx: &mut TrapContext) -> TrapAction {
+    log!(
+        "{} at {:x} accessing {:x}",
+        Trap::from_scause(trapctx.scause),
+        trapctx.sepc,
+        trapctx.stval
+    );
+    TrapAction::Kill
+}
+
+/// The length of the instruction at `pc`. Compressed instructions are two
+/// bytes, and have something other than 0b11 in their low bits.
+unsafe fn instruction_len(pc: usize) -> usize {
+    if (pc as *const u16).read_volatile() & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Run the handler for a trap, and set where to resume.
+fn dispatch(trapctx: &mut TrapContext) {
+    let trap = Trap::from_scause(trapctx.scause);
+    let handler = match HANDLERS.read().slot(trap) {
+        Some(Some(handler)) => *handler,
+        _ => panic!(
+            "Unknown trap on hart {}! Reason: {:x}: {}",
+            trapctx.hartid, trapctx.scause, trap
+        ),
+    };
+    match handler(trapctx) {
+        TrapAction::Resume => {}
+        TrapAction::Skip => trapctx.sepc += unsafe { instruction_len(trapctx.sepc) },
+        TrapAction::Kill => panic!(
+            "Trap killed the current task: {:x}: {} at {:x}",
+            trapctx.scause, trap, trapctx.sepc
+        ),
     }
 }
 
 #[cfg(not(test))]
 #[no_mangle]
-pub extern "C" fn rtrap(
-    mepc: usize,
-    mtval: usize,
-    mcause: usize,
-    mhartid: usize,
-    mstatus: usize,
-    trapctx: *mut TrapContext,
-) -> usize {
-    log!("Trap mepc: {:x}", mepc);
-    log!("Trap mtval: {:x}", mtval);
-    log!("Trap mcause: {:x}: {}", mcause, trap_reason(mcause));
-    log!("Trap mhartid: {:x}", mhartid);
-    log!("Trap mstatus: {:x}", mstatus);
-    log!("Trap Context: {:#x?}", unsafe { *trapctx });
-    match mcause {
-        SUPERVISOR_TIMER_INTERRUPT => {
-            interrupts::disable();
-            let mut stime: usize;
-            let mut stimecmp: usize;
-            //unsafe {
-            //    //asm!("csrr mtime, $0; csrr mtimecmp, $1" : "=r"(mtime), "=r"(mtimecmp):);
-            //    mtimecmp = mtime + 0x10_000_000;
-            //    //asm!("csrw $0, mtimecmp" : : "r"(mtimecmp));
-            //}
-        }
-        _ => {
-            panic!(
-                "Unknown trap! Reason: {:x}: {}",
-                mcause,
-                trap_reason(mcause)
+pub extern "C" fn rtrap(trapctx: *mut TrapContext) {
+    let trapctx = unsafe { &mut *trapctx };
+    // trap.S leaves vector state dirty if it didn't fit.
+    if trapctx.sstatus & SSTATUS_VS == SSTATUS_VS {
+        panic!(
+            "Vector registers of {} bytes don't fit the trap context",
+            trapctx.vector.vlenb
+        );
+    }
+    dispatch(trapctx);
+}
+
+/// trap.S calls this for a trap taken while handling a trap.
+#[cfg(not(test))]
+#[no_mangle]
+pub extern "C" fn rtrap_nested(scause: usize, sepc: usize, stval: usize) -> ! {
+    panic!(
+        "{} in a trap handler on hart {} at {:x} accessing {:x}",
+        Trap::from_scause(scause),
+        crate::hart::id(),
+        sepc,
+        stval
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    fn resume(_: &mut TrapContext) -> TrapAction {
+        TrapAction::Resume
+    }
+
+    fn skip(_: &mut TrapContext) -> TrapAction {
+        TrapAction::Skip
+    }
+
+    fn kill(_: &mut TrapContext) -> TrapAction {
+        TrapAction::Kill
+    }
+
+    const INTERRUPT: usize = 1 << (mem::size_of::<usize>() * 8 - 1);
+
+    const INTERRUPTS: [(u64, InterruptCause); 10] = [
+        (0, InterruptCause::UserSoftware),
+        (1, InterruptCause::SupervisorSoftware),
+        (3, InterruptCause::MachineSoftware),
+        (4, InterruptCause::UserTimer),
+        (5, InterruptCause::SupervisorTimer),
+        (7, InterruptCause::MachineTimer),
+        (8, InterruptCause::UserExternal),
+        (9, InterruptCause::SupervisorExternal),
+        (11, InterruptCause::MachineExternal),
+        (13, InterruptCause::CounterOverflow),
+    ];
+
+    const EXCEPTIONS: [(u64, ExceptionCause); 16] = [
+        (0, ExceptionCause::InstructionAddressMisaligned),
+        (1, ExceptionCause::InstructionAccessFault),
+        (2, ExceptionCause::IllegalInstruction),
+        (3, ExceptionCause::Breakpoint),
+        (4, ExceptionCause::LoadAddressMisaligned),
+        (5, ExceptionCause::LoadAccessFault),
+        (6, ExceptionCause::StoreAmoAddressMisaligned),
+        (7, ExceptionCause::StoreAmoAccessFault),
+        (8, ExceptionCause::EcallFromUMode),
+        (9, ExceptionCause::EcallFromSMode),
+        (11, ExceptionCause::EcallFromMMode),
+        (12, ExceptionCause::InstructionPageFault),
+        (13, ExceptionCause::LoadPageFault),
+        (15, ExceptionCause::StoreAmoPageFault),
+        (18, ExceptionCause::SoftwareCheck),
+        (19, ExceptionCause::HardwareError),
+    ];
+
+    #[test]
+    fn decode_causes() {
+        for &xlen in &[32, 64] {
+            let interrupt = 1u64 << (xlen - 1);
+            for &(code, cause) in INTERRUPTS.iter() {
+                let trap = Trap::from_cause(interrupt | code, xlen);
+                assert_eq!(trap, Trap::Interrupt(cause));
+                assert_eq!(cause.code() as u64, code);
+                assert!(!std::format!("{}", trap).starts_with("Unknown"));
+            }
+            for &(code, cause) in EXCEPTIONS.iter() {
+                let trap = Trap::from_cause(code, xlen);
+                assert_eq!(trap, Trap::Exception(cause));
+                assert_eq!(cause.code() as u64, code);
+                assert!(!std::format!("{}", trap).starts_with("Unknown"));
+            }
+            assert_eq!(
+                Trap::from_cause(interrupt | 2, xlen),
+                Trap::Interrupt(InterruptCause::Unknown(2))
+            );
+            assert_eq!(
+                Trap::from_cause(10, xlen),
+                Trap::Exception(ExceptionCause::Unknown(10))
             );
         }
+        // Bit 56 isn't the interrupt bit.
+        assert_eq!(
+            Trap::from_cause(1 << 56 | 5, 64),
+            Trap::Exception(ExceptionCause::Unknown(1 << 56 | 5))
+        );
+        // On RV32 bit 31 is.
+        assert_eq!(
+            Trap::from_cause(0x8000_0005, 32),
+            Trap::Interrupt(InterruptCause::SupervisorTimer)
+        );
+        assert_eq!(
+            Trap::from_scause(INTERRUPT | 9),
+            Trap::Interrupt(InterruptCause::SupervisorExternal)
+        );
+    }
+
+    /// Dispatch a trap and return where it resumes.
+    fn trap(scause: usize, sepc: usize) -> usize {
+        let mut ctx = TrapContext::empty();
+        ctx.scause = scause;
+        ctx.sepc = sepc;
+        dispatch(&mut ctx);
+        ctx.sepc
+    }
+
+    #[test]
+    fn dispatch_by_cause() {
+        // An ebreak, then a compressed c.ebreak.
+        let code: [u16; 3] = [0x0073, 0x0010, 0x9002];
+        let pc = code.as_ptr() as usize;
+        let timer = Trap::Interrupt(InterruptCause::SupervisorTimer);
+        let breakpoint = Trap::Exception(ExceptionCause::Breakpoint);
+        register(timer, resume);
+        assert_eq!(trap(INTERRUPT | 5, pc), pc);
+        // Interrupt and exception codes don't collide.
+        assert!(register(breakpoint, skip).is_none());
+        assert_eq!(trap(3, pc), pc + 4);
+        assert_eq!(trap(3, pc + 4), pc + 6);
+        assert!(register(breakpoint, kill).is_some());
+        let killed = std::panic::catch_unwind(move || trap(3, pc));
+        assert!(killed.is_err());
+    }
+
+    #[test]
+    fn context_layout() {
+        // Offsets trap.S uses.
+        let ctx = TrapContext::empty();
+        let base = &ctx as *const _ as usize;
+        let offset = |field: *const u8| field as usize - base;
+        assert_eq!(offset(&ctx.trap_sp as *const _ as *const u8), 32 * 8);
+        assert_eq!(offset(&ctx.hartid as *const _ as *const u8), 33 * 8);
+        assert_eq!(offset(&ctx.sepc as *const _ as *const u8), 35 * 8);
+        assert_eq!(offset(&ctx.scause as *const _ as *const u8), 38 * 8);
+        assert_eq!(offset(&ctx.fp as *const _ as *const u8), 39 * 8);
+        assert_eq!(offset(&ctx.fp.restore as *const _ as *const u8), 72 * 8);
+        assert_eq!(offset(&ctx.vector.restore as *const _ as *const u8), 78 * 8);
+        assert_eq!(offset(ctx.vector.regs.as_ptr()), 79 * 8);
     }
-    let mut cycle: usize;
-    unsafe { asm!("rdcycle $0" : "=r"(cycle)); }
 
-    log!("Trap cycle: {:x} ", cycle);
+    #[test]
+    fn per_hart_contexts() {
+        let (ctx0, ctx1) = unsafe { (prepare_context(0), prepare_context(1)) };
+        let (ctx0, ctx1) = unsafe { (&*ctx0.unwrap(), &*ctx1.unwrap()) };
+        assert_eq!((ctx0.hartid(), ctx1.hartid()), (0, 1));
+        // Each stack pointer is the aligned top of a stack of its own.
+        assert_eq!(ctx0.trap_sp % 16, 0);
+        assert!(ctx1.trap_sp >= ctx0.trap_sp + TRAP_STACK_SIZE);
+        assert!(unsafe { prepare_context(MAX_HARTS) }.is_none());
+    }
+
+    #[test]
+    fn lazy_fp_and_vector_state() {
+        let mut ctx = TrapContext::empty();
+        assert!(ctx.fp().is_none() && ctx.vector().is_none());
+        ctx.fp_mut().f[0] = 1;
+        assert_eq!(ctx.fp.restore, 1);
+        assert_eq!(ctx.sstatus & SSTATUS_FS, SSTATUS_FS_CLEAN);
+        assert_eq!(ctx.fp().unwrap().f[0], 1);
+        ctx.vector_mut().vl = 4;
+        assert_eq!(ctx.sstatus & SSTATUS_VS, SSTATUS_VS_CLEAN);
+        assert_eq!(ctx.vector().unwrap().vl, 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown trap")]
+    fn unhandled() {
+        trap(INTERRUPT | 14, 0);
+    }
+
+    #[test]
+    fn register_unknown() {
+        let unknown = Trap::Exception(ExceptionCause::Unknown(42));
+        assert!(std::panic::catch_unwind(|| register(unknown, resume)).is_err());
+        // The table is still readable.
+        assert!(HANDLERS.read().slot(unknown).is_none());
+    }
 
-    //let mut time: usize;
-    let mtime = 0x0200_bff8 as *const u64;
-    //unsafe { asm!("rdtime $0" : "=r"(time)); }
-    let time = unsafe { mtime.read_volatile() };
-    log!("Trap time: {:x} ", time);
-    mepc
+    #[test]
+    fn kernel_handlers() {
+        let mut ctx = TrapContext::empty();
+        ctx.regs[17] = 0x10;
+        assert_eq!(ecall(&mut ctx), TrapAction::Skip);
+        assert_eq!(ctx.regs[10] as isize, ECALL_NOT_SUPPORTED);
+        assert_eq!(breakpoint(&mut ctx), TrapAction::Skip);
+        ctx.scause = 13;
+        assert_eq!(page_fault(&mut ctx), TrapAction::Kill);
+        assert_eq!(illegal_instruction(&mut ctx), TrapAction::Kill);
+    }
 }
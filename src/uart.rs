@@ -1,60 +1,171 @@
 use crate::mmio;
 use crate::mmio::MmioRegion;
-use crate::mutex::Mutex;
+use crate::mutex::{ByteRing, IrqMutex, Once};
 use core::cell::RefCell;
 use core::fmt::{Error, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub struct Uart<'a> {
-    rgn: Option<MmioRegion<'a>>,
+    rgn: MmioRegion<'a>,
 }
 
-pub static LOGGER: Mutex<Uart> = Mutex::new(Uart { rgn: None });
+/// The console UART. Empty until the device tree tells us where it is.
+pub static LOGGER: Once<IrqMutex<Uart>> = Once::new();
+
+const CONSOLE_SIZE: usize = 16 * 1024;
+/// Longer lines may be split up by other harts' output.
+const LINE_SIZE: usize = 128;
+
+/// Console output waiting for the UART. Writers only push here, and whoever
+/// can take the UART lock writes it out, so logging from a trap handler never
+/// spins on a lock the interrupted code holds. Output from before the UART is
+/// set up waits here too.
+static CONSOLE: ByteRing<CONSOLE_SIZE> = ByteRing::new();
+/// Bytes dropped because the ring was full.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Set up the console UART at the given MMIO region.
+pub fn init(slc: &'static mut [u8]) {
+    LOGGER.call_once(move || IrqMutex::new(Uart::new(slc)));
+    flush();
+}
+
+/// Write out buffered console output, unless somebody else is using the UART.
+/// Whoever is will write it out instead.
+pub fn flush() {
+    let uart = match LOGGER.get() {
+        Some(uart) => uart,
+        None => return,
+    };
+    // Output pushed after the holder's last look at the ring is ours to write.
+    while !CONSOLE.is_empty() {
+        match uart.try_lock() {
+            Some(mut uart) => uart.drain(),
+            None => return,
+        }
+    }
+}
+
+/// Write out all buffered console output, waiting for the UART if need be.
+/// The panic handler uses this, trap handlers should use `flush`.
+pub fn sync() {
+    if let Some(uart) = LOGGER.get() {
+        uart.lock_checked().unwrap_or_else(|e| e.into_inner()).drain();
+    }
+}
+
+/// A writer to the console. Output is pushed a line at a time, and written
+/// out when the writer is dropped.
+pub struct Console {
+    line: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Console {
+    pub const fn new() -> Self {
+        Self {
+            line: [0; LINE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self) {
+        let mut rest = &self.line[..self.len];
+        while !rest.is_empty() {
+            let mut pushed = CONSOLE.push(rest);
+            if pushed == 0 {
+                // Make room if the UART is free, otherwise give up.
+                flush();
+                pushed = CONSOLE.push(rest);
+                if pushed == 0 {
+                    DROPPED.fetch_add(rest.len(), Ordering::Relaxed);
+                    break;
+                }
+            }
+            rest = &rest[pushed..];
+        }
+        self.len = 0;
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        for &byte in s.as_bytes() {
+            if self.len == LINE_SIZE {
+                self.push();
+            }
+            self.line[self.len] = byte;
+            self.len += 1;
+            if byte == b'\n' {
+                self.push();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        self.push();
+        flush();
+    }
+}
 
 impl<'a> Uart<'a> {
     pub fn new(slc: &'a mut [u8]) -> Self {
-        let mut n = Self {
-            rgn: Some(MmioRegion::from_slice(slc)),
+        let n = Self {
+            rgn: MmioRegion::from_slice(slc),
         };
         n.configure();
         n
     }
 
-    pub fn init(&mut self, slc: &'a mut [u8]) {
-        self.rgn = Some(MmioRegion::from_slice(slc));
-        self.configure();
-    }
-
-    fn configure(&mut self) {
-        if let Some(rgn) = &self.rgn {
-            let lcr: u8 = 1 | (1 << 1);
-            rgn.write(3, lcr);
+    fn configure(&self) {
+        let rgn = &self.rgn;
+        let lcr: u8 = 1 | (1 << 1);
+        rgn.write(3, lcr);
 
-            // Set FIFO control
-            rgn.write(2, 1);
+        // Set FIFO control
+        rgn.write(2, 1);
 
-            // Enable receiver buffer interrupts.
-            rgn.write(1, 1);
+        // Enable receiver buffer interrupts.
+        rgn.write(1, 1);
 
-            // Set divisor & baud.
-            let divisor: u16 = 592;
-            let divisor_least: u8 = (divisor & 0xff) as u8;
-            let divisor_most: u8 = (divisor >> 8) as u8;
+        // Set divisor & baud.
+        let divisor: u16 = 592;
+        let divisor_least: u8 = (divisor & 0xff) as u8;
+        let divisor_most: u8 = (divisor >> 8) as u8;
 
-            // Set DLAB
-            rgn.write(3, lcr | 1 << 7);
+        // Set DLAB
+        rgn.write(3, lcr | 1 << 7);
 
-            // Set divisor bits
-            rgn.write(0, divisor_least);
-            rgn.write(0, divisor_most);
+        // Set divisor bits
+        rgn.write(0, divisor_least);
+        rgn.write(0, divisor_most);
 
-            // Set enabled again.
-            rgn.write(3, lcr);
-        }
+        // Set enabled again.
+        rgn.write(3, lcr);
     }
 
     fn putc(&self, byte: u8) {
-        if let Some(rgn) = &self.rgn {
-            rgn.write(0, byte);
+        self.rgn.write(0, byte);
+    }
+
+    /// Write out everything in the console ring.
+    fn drain(&mut self) {
+        let mut buf = [0; 64];
+        loop {
+            let len = CONSOLE.pop(&mut buf);
+            if len == 0 {
+                break;
+            }
+            for &byte in &buf[..len] {
+                self.putc(byte);
+            }
+        }
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped != 0 {
+            let _ = writeln!(self, "[console dropped {} bytes]", dropped);
         }
     }
 }